@@ -1,6 +1,14 @@
+use crate::functions::layout::{
+    DiskLayout, ExistingEsp, LayoutPartition, Lvm, Size, Subvolume, LVM_FILESYSTEM,
+};
+use crate::functions::partition;
+use crate::internal::auto::DEFAULT_CONFIG_LABEL;
+use crate::internal::secret::Secret;
 use clap::{ArgEnum, Args, Parser, Subcommand};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::os::unix::fs::FileTypeExt;
 use std::path::PathBuf;
+use std::str::FromStr;
 
 #[derive(Debug, Parser)]
 #[clap(name="jade", version=env!("CARGO_PKG_VERSION"), about=env!("CARGO_PKG_DESCRIPTION"), author=env!("CARGO_PKG_AUTHORS"))]
@@ -18,17 +26,41 @@ pub enum Command {
     #[clap(name = "partition")]
     Partition(PartitionArgs),
 
+    /// List the disks jade can install to
+    #[clap(name = "disks")]
+    Disks {
+        /// Print the disks as JSON
+        #[clap(long)]
+        json: bool,
+    },
+
     /// Install base packages, optionally define a different kernel
     #[clap(name = "install-base")]
     InstallBase(InstallBaseArgs),
 
     /// Generate fstab file for mounting partitions
     #[clap(name = "genfstab")]
-    GenFstab,
+    GenFstab {
+        /// How fstab refers to the filesystems, UUID is used where there is no PARTUUID or label
+        #[clap(long, arg_enum, default_value = "uuid")]
+        by: FstabReference,
+    },
+
+    /// Unmount an install into an image file and detach its loop device
+    #[clap(name = "detach-image")]
+    DetachImage {
+        /// Convert the image to qcow2 at this path
+        #[clap(long, value_name = "PATH")]
+        qcow2: Option<PathBuf>,
+    },
 
     /// Setup Timeshift
     #[clap(name = "setup-timeshift")]
-    SetupTimeshift { bootloader: String },
+    SetupTimeshift {
+        /// The bootloader that is installed
+        #[clap(arg_enum)]
+        bootloader: BootloaderType,
+    },
 
     /// Install the bootloader
     #[clap(name = "bootloader")]
@@ -73,6 +105,22 @@ pub enum Command {
     Config {
         /// The config file to read
         config: PathBuf,
+
+        /// Don't ask for confirmation before erasing disks
+        #[clap(long, short)]
+        yes: bool,
+    },
+
+    /// Find a config on the kernel command line or labeled media and install it unattended
+    #[clap(name = "auto")]
+    Auto {
+        /// The filesystem label of the media to look for jade.json on
+        #[clap(long, default_value = DEFAULT_CONFIG_LABEL)]
+        label: String,
+
+        /// Seconds to wait before installing, defaults to jade.timeout= on the kernel command line or 0
+        #[clap(long)]
+        timeout: Option<u64>,
     },
 
     /// Install a graphical desktop
@@ -86,15 +134,20 @@ pub enum Command {
 
 #[derive(Debug, Args)]
 pub struct PartitionArgs {
-    /// If jade should automatically partition (mode = auto)
-    /// or the user manually partitioned it (mode = manual)
+    /// If jade should automatically partition the whole device (mode = auto), install into
+    /// its free space next to another OS (mode = alongside) or use the partitions the user
+    /// made (mode = manual)
     #[clap(arg_enum)]
     pub mode: PartitionMode,
 
-    /// The device to partition
+    /// The device to partition, or an image file to install into through a loop device
     #[clap(required_if_eq("mode", "PartitionMode::Auto"))]
     pub device: PathBuf,
 
+    /// Create the image file with this size, e.g. 20GiB, if it doesn't exist yet
+    #[clap(long, value_name = "SIZE")]
+    pub image_size: Option<Size>,
+
     /// If the install destination should be partitioned with EFI
     #[clap(long)]
     pub efi: bool,
@@ -102,15 +155,109 @@ pub struct PartitionArgs {
     #[clap(long)]
     pub unakite: bool,
 
+    /// Encrypt the root partition with LUKS2, using the passphrase in this file
+    #[clap(long, value_name = "PASSPHRASE_FILE")]
+    pub encrypt: Option<PathBuf>,
+
+    /// The filesystem to format the root with, bcachefs isn't supported as mainline
+    /// kernels can't mount it anymore
+    #[clap(long, arg_enum, default_value = "btrfs")]
+    pub root_filesystem: RootFilesystem,
+
+    /// Put the root on an LVM logical volume, in manual mode on the partition given with the
+    /// lvm filesystem
+    #[clap(long, conflicts_with = "unakite")]
+    pub lvm: bool,
+
+    /// Add swap of this size, e.g. 8GiB, as a partition or logical volume
+    #[clap(long, value_name = "SIZE")]
+    pub swap: Option<Size>,
+
+    /// Put the swap into a swapfile on the root instead
+    #[clap(long, requires = "swap")]
+    pub swapfile: bool,
+
+    /// Resume from the swap after hibernating
+    #[clap(long, requires = "swap")]
+    pub hibernate: bool,
+
+    /// When installing alongside another OS, shrink this partition of it to make room
+    #[clap(long, value_name = "PARTITION", requires = "shrink-by")]
+    pub shrink: Option<usize>,
+
+    /// How much space to take from the partition that gets shrunk, e.g. 50GiB
+    #[clap(long, value_name = "SIZE", requires = "shrink")]
+    pub shrink_by: Option<Size>,
+
+    /// Build a software RAID of this level across the device and every --raid-device
+    #[clap(long, arg_enum, requires = "raid-devices", conflicts_with = "unakite")]
+    pub raid: Option<RaidLevel>,
+
+    /// Another disk of the RAID, can be given more than once
+    #[clap(long = "raid-device", value_name = "DEVICE", requires = "raid")]
+    pub raid_devices: Vec<PathBuf>,
+
+    /// Put a mountpoint on a whole other disk, as MOUNTPOINT:DEVICE:FILESYSTEM,
+    /// e.g. /home:/dev/sdb:ext4, can be given more than once
+    #[clap(long = "disk", value_name = "SPEC", parse(try_from_str = parse_disk))]
+    pub disks: Vec<DiskLayout>,
+
+    /// Mount this existing EFI system partition, e.g. the one of Windows on another disk,
+    /// instead of creating one, `auto` finds it
+    #[clap(long, value_name = "DEVICE|auto", requires = "efi")]
+    pub esp: Option<ExistingEsp>,
+
+    /// Whether the RAID is built with mdadm or btrfs' own multi-device profiles
+    #[clap(long, arg_enum, default_value = "mdadm")]
+    pub raid_backend: RaidBackend,
+
+    /// Wipe old filesystem, LUKS and RAID signatures off the disks before partitioning,
+    /// `discard` also discards every block of SSDs and `zero` zeroes the start and end of the disks
+    #[clap(long, arg_enum, value_name = "METHOD")]
+    pub wipe: Option<WipeMethod>,
+
+    /// Also unlock the encrypted root with the TPM2 chip, bound to the Secure Boot state
+    #[clap(long, requires = "encrypt")]
+    pub tpm2: bool,
+
+    /// Also unlock the encrypted root with a FIDO2 token
+    #[clap(long, requires = "encrypt")]
+    pub fido2: bool,
+
+    /// Don't ask for confirmation before erasing the device
+    #[clap(long, short)]
+    pub yes: bool,
+
     /// The partitions to use for manual partitioning
-    #[clap(required_if_eq("mode", "Partition::Manual"), parse(try_from_str = parse_partitions))]
+    #[clap(required_if_eq("mode", "Partition::Manual"), parse(try_from_str = parse_partition))]
     pub partitions: Vec<Partition>,
 }
 
 #[derive(Debug, Args)]
 pub struct InstallBaseArgs {
-    #[clap(long)]
-    pub kernel: String,
+    /// The kernel to install
+    #[clap(long, arg_enum, default_value = "linux")]
+    pub kernel: Kernel,
+}
+
+#[derive(Debug, ArgEnum, Copy, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Kernel {
+    #[default]
+    #[clap(name = "linux")]
+    #[serde(rename = "linux")]
+    Linux,
+
+    #[clap(name = "linux-lts", aliases = ["lts"])]
+    #[serde(rename = "linux-lts", alias = "lts")]
+    LinuxLts,
+
+    #[clap(name = "linux-zen", aliases = ["zen"])]
+    #[serde(rename = "linux-zen", alias = "zen")]
+    LinuxZen,
+
+    #[clap(name = "linux-hardened", aliases = ["hardened"])]
+    #[serde(rename = "linux-hardened", alias = "hardened")]
+    LinuxHardened,
 }
 
 #[derive(Debug, Args)]
@@ -132,11 +279,26 @@ pub struct UnakiteArgs {
     pub bootdev: String,
 }
 
-#[derive(Debug)]
+/// A partition for manual partitioning, written on the command line as
+/// `MOUNTPOINT:BLOCKDEVICE:FILESYSTEM[:OPTIONS]`. OPTIONS is a comma separated list of mount
+/// options and `label=NAME`, `mkfs=ARG` or `subvolume=NAME=MOUNTPOINT` entries.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Partition {
     pub mountpoint: String,
     pub blockdevice: String,
     pub filesystem: String,
+    /// Options to mount the partition (and its subvolumes) with, e.g. `noatime`
+    #[serde(default)]
+    pub mount_options: Vec<String>,
+    /// Extra arguments passed to mkfs
+    #[serde(default)]
+    pub mkfs_options: Vec<String>,
+    /// Filesystem label
+    #[serde(default)]
+    pub label: Option<String>,
+    /// btrfs subvolumes to create and mount
+    #[serde(default)]
+    pub subvolumes: Vec<Subvolume>,
 }
 
 impl Partition {
@@ -145,17 +307,254 @@ impl Partition {
             mountpoint,
             blockdevice,
             filesystem,
+            mount_options: Vec::new(),
+            mkfs_options: Vec::new(),
+            label: None,
+            subvolumes: Vec::new(),
+        }
+    }
+}
+
+impl Partition {
+    /// Makes the mountpoints relative to the installed system. They used to be given below
+    /// /mnt, like /mnt/home, which is still accepted.
+    pub fn strip_mnt(mut self) -> Self {
+        self.mountpoint = strip_mnt(&self.mountpoint);
+        for subvolume in self.subvolumes.iter_mut() {
+            subvolume.mountpoint = strip_mnt(&subvolume.mountpoint);
+        }
+        self
+    }
+
+    /// Checks everything about a single partition that doesn't depend on the other ones
+    pub fn validate(&self) -> Result<(), String> {
+        self.check_spec()?;
+        let blockdevice = &self.blockdevice;
+        match std::fs::metadata(blockdevice) {
+            Ok(metadata) if metadata.file_type().is_block_device() => Ok(()),
+            Ok(_) => Err(format!("{blockdevice} is not a block device")),
+            Err(_) => Err(format!("The block device {blockdevice} doesn't exist")),
+        }
+    }
+
+    /// The checks of `validate` that only look at the spec itself
+    fn check_spec(&self) -> Result<(), String> {
+        let blockdevice = &self.blockdevice;
+        partition::check_supported(&self.filesystem)?;
+        let mut filesystems = partition::filesystems();
+        filesystems.push(LVM_FILESYSTEM);
+        if !filesystems.contains(&self.filesystem.as_str()) {
+            return Err(format!(
+                "Unknown filesystem {:?} for {blockdevice}, expected one of {}",
+                self.filesystem,
+                filesystems.join(", ")
+            ));
+        }
+        if self.is_mounted() && !self.mountpoint.starts_with('/') {
+            return Err(format!(
+                "Mountpoint {:?} of {blockdevice} is not an absolute path",
+                self.mountpoint
+            ));
+        }
+        if self.filesystem == LVM_FILESYSTEM
+            && (self.label.is_some()
+                || !self.mkfs_options.is_empty()
+                || !self.mount_options.is_empty())
+        {
+            return Err(format!(
+                "{blockdevice} holds an LVM volume group, options go on its logical volumes"
+            ));
+        }
+        if !self.subvolumes.is_empty() && self.filesystem != "btrfs" {
+            return Err(format!(
+                "{blockdevice} has subvolumes but is formatted as {}, not btrfs",
+                self.filesystem
+            ));
+        }
+        for subvolume in &self.subvolumes {
+            if subvolume.name.is_empty() {
+                return Err(format!("A subvolume of {blockdevice} has no name"));
+            }
+            if !subvolume.mountpoint.starts_with('/') {
+                return Err(format!(
+                    "Mountpoint {:?} of subvolume {} is not an absolute path",
+                    subvolume.mountpoint, subvolume.name
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the partition itself gets mounted, swap and LVM partitions don't
+    fn is_mounted(&self) -> bool {
+        self.filesystem != "swap" && self.filesystem != LVM_FILESYSTEM
+    }
+
+    /// Where the partition and its subvolumes get mounted
+    fn mountpoints(&self) -> Vec<&str> {
+        let mut mountpoints = Vec::new();
+        if self.is_mounted() {
+            mountpoints.push(self.mountpoint.as_str());
+        }
+        for subvolume in &self.subvolumes {
+            if !mountpoints.contains(&subvolume.mountpoint.as_str()) {
+                mountpoints.push(&subvolume.mountpoint);
+            }
+        }
+        mountpoints
+    }
+}
+
+/// Turns a mountpoint below /mnt into the one in the installed system
+fn strip_mnt(mountpoint: &str) -> String {
+    match mountpoint.strip_prefix("/mnt") {
+        Some("") => String::from("/"),
+        Some(rest) if rest.starts_with('/') => rest.to_string(),
+        _ => mountpoint.to_string(),
+    }
+}
+
+/// Checks the partitions for manual partitioning, each of them and that they don't clash,
+/// `lvm` holds the logical volumes created on a partition with the lvm filesystem
+pub fn validate_partitions(partitions: &[Partition], lvm: Option<&Lvm>) -> Result<(), String> {
+    for partition in partitions {
+        partition.validate()?;
+    }
+    check_clashes(partitions, lvm)
+}
+
+/// Checks that no block device or mountpoint is used by more than one partition or logical
+/// volume, and that there are logical volumes for an lvm partition
+fn check_clashes(partitions: &[Partition], lvm: Option<&Lvm>) -> Result<(), String> {
+    let pvs = partitions
+        .iter()
+        .filter(|p| p.filesystem == LVM_FILESYSTEM)
+        .count();
+    let volumes = match (lvm, pvs) {
+        (_, 0) => Vec::new(),
+        (Some(lvm), 1) => {
+            lvm.validate()?;
+            lvm.volumes.iter().collect()
+        }
+        (None, _) => {
+            return Err(String::from(
+                "A partition has the lvm filesystem but no logical volumes are given",
+            ))
+        }
+        (Some(_), _) => {
+            return Err(String::from(
+                "Only one partition can hold the LVM volume group",
+            ))
+        }
+    };
+    let filesystems = partition::filesystems();
+    for volume in &volumes {
+        if !filesystems.contains(&volume.filesystem.as_str()) {
+            return Err(format!(
+                "Unknown filesystem {:?} for logical volume {}",
+                volume.filesystem, volume.name
+            ));
+        }
+    }
+    let mut mountpoints: Vec<&str> = volumes
+        .iter()
+        .filter_map(|volume| volume.mountpoint.as_deref())
+        .collect();
+    if let Some(mountpoint) = mountpoints.iter().find(|m| !m.starts_with('/')) {
+        return Err(format!("Mountpoint {mountpoint} is not an absolute path"));
+    }
+    let mut blockdevices: Vec<&str> = Vec::new();
+    for partition in partitions {
+        if blockdevices.contains(&partition.blockdevice.as_str()) {
+            return Err(format!(
+                "The block device {} is used more than once",
+                partition.blockdevice
+            ));
+        }
+        blockdevices.push(&partition.blockdevice);
+        for mountpoint in partition.mountpoints() {
+            if mountpoints.contains(&mountpoint) {
+                return Err(format!("Mountpoint {mountpoint} is used more than once"));
+            }
+            mountpoints.push(mountpoint);
         }
     }
+    Ok(())
 }
 
-pub fn parse_partitions(s: &str) -> Result<Partition, &'static str> {
-    println!("{}", s);
-    Ok(Partition::new(
-        s.split(':').collect::<Vec<&str>>()[0].to_string(),
-        s.split(':').collect::<Vec<&str>>()[1].to_string(),
-        s.split(':').collect::<Vec<&str>>()[2].to_string(),
-    ))
+/// Parses and checks a partition given on the command line
+fn parse_partition(s: &str) -> Result<Partition, String> {
+    let partition: Partition = s.parse()?;
+    partition.validate()?;
+    Ok(partition)
+}
+
+/// Parses another disk of an automatic layout, it gets a single partition spanning the disk
+fn parse_disk(s: &str) -> Result<DiskLayout, String> {
+    let partition: Partition = s.parse()?;
+    if s.splitn(4, ':').count() > 3 {
+        return Err(String::from(
+            "other disks are given as MOUNTPOINT:DEVICE:FILESYSTEM, without options",
+        ));
+    }
+    let filesystem = partition.filesystem.as_str();
+    if partition::NOFORMAT.contains(&filesystem) || filesystem == LVM_FILESYSTEM {
+        return Err(format!(
+            "other disks are partitioned and formatted from scratch, they can't use {filesystem:?}"
+        ));
+    }
+    partition.validate()?;
+    Ok(DiskLayout {
+        device: PathBuf::from(partition.blockdevice),
+        partitions: vec![LayoutPartition::new(
+            Size::Remainder,
+            &partition.filesystem,
+            Some(&partition.mountpoint),
+        )],
+    })
+}
+
+impl FromStr for Partition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.splitn(4, ':');
+        let mut partition = match (fields.next(), fields.next(), fields.next()) {
+            (Some(mountpoint), Some(blockdevice), Some(filesystem)) => Partition::new(
+                mountpoint.to_string(),
+                blockdevice.to_string(),
+                filesystem.to_string(),
+            ),
+            _ => {
+                return Err(format!(
+                    "Invalid partition {s:?}, expected MOUNTPOINT:BLOCKDEVICE:FILESYSTEM[:OPTIONS]"
+                ))
+            }
+        };
+        for option in fields.next().unwrap_or("").split(',') {
+            if option.is_empty() {
+                continue;
+            } else if let Some(label) = option.strip_prefix("label=") {
+                partition.label = Some(label.to_string());
+            } else if let Some(arg) = option.strip_prefix("mkfs=") {
+                partition.mkfs_options.push(arg.to_string());
+            } else if let Some(subvolume) = option.strip_prefix("subvolume=") {
+                match subvolume.split_once('=') {
+                    Some((name, mountpoint)) => {
+                        partition.subvolumes.push(Subvolume::new(name, mountpoint))
+                    }
+                    None => {
+                        return Err(format!(
+                            "Invalid subvolume {subvolume:?}, expected subvolume=NAME=MOUNTPOINT"
+                        ))
+                    }
+                }
+            } else {
+                partition.mount_options.push(option.to_string());
+            }
+        }
+        Ok(partition.strip_mnt())
+    }
 }
 
 #[derive(Debug, ArgEnum, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize)]
@@ -164,6 +563,142 @@ pub enum PartitionMode {
     Auto,
     #[clap(name = "manual")]
     Manual,
+    #[clap(name = "alongside")]
+    Alongside,
+}
+
+#[derive(Debug, ArgEnum, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum BootloaderType {
+    #[clap(name = "grub-efi")]
+    #[serde(rename = "grub-efi")]
+    GrubEfi,
+
+    #[clap(name = "grub-legacy")]
+    #[serde(rename = "grub-legacy")]
+    GrubLegacy,
+
+    #[clap(name = "refind")]
+    #[serde(rename = "refind")]
+    Refind,
+}
+
+#[derive(Debug, ArgEnum, Copy, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub enum RootFilesystem {
+    #[default]
+    #[clap(name = "btrfs")]
+    #[serde(rename = "btrfs")]
+    Btrfs,
+
+    #[clap(name = "ext4")]
+    #[serde(rename = "ext4")]
+    Ext4,
+
+    #[clap(name = "xfs")]
+    #[serde(rename = "xfs")]
+    Xfs,
+
+    #[clap(name = "f2fs")]
+    #[serde(rename = "f2fs")]
+    F2fs,
+
+    #[clap(name = "bcachefs")]
+    #[serde(rename = "bcachefs")]
+    Bcachefs,
+}
+
+impl RootFilesystem {
+    /// The filesystem as mkfs and mount call it
+    pub fn name(self) -> &'static str {
+        match self {
+            RootFilesystem::Btrfs => "btrfs",
+            RootFilesystem::Ext4 => "ext4",
+            RootFilesystem::Xfs => "xfs",
+            RootFilesystem::F2fs => "f2fs",
+            RootFilesystem::Bcachefs => "bcachefs",
+        }
+    }
+}
+
+#[derive(Debug, ArgEnum, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum RaidLevel {
+    #[clap(name = "raid0")]
+    #[serde(rename = "raid0")]
+    Raid0,
+
+    #[clap(name = "raid1")]
+    #[serde(rename = "raid1")]
+    Raid1,
+
+    #[clap(name = "raid10")]
+    #[serde(rename = "raid10")]
+    Raid10,
+}
+
+impl RaidLevel {
+    /// The level as mdadm and mkfs.btrfs call it
+    pub fn name(self) -> &'static str {
+        match self {
+            RaidLevel::Raid0 => "raid0",
+            RaidLevel::Raid1 => "raid1",
+            RaidLevel::Raid10 => "raid10",
+        }
+    }
+
+    /// The least number of disks an array of this level needs
+    pub fn min_devices(self) -> usize {
+        match self {
+            RaidLevel::Raid0 | RaidLevel::Raid1 => 2,
+            RaidLevel::Raid10 => 4,
+        }
+    }
+
+    /// Whether the array survives losing a disk, so every disk has to be bootable
+    pub fn redundant(self) -> bool {
+        self != RaidLevel::Raid0
+    }
+}
+
+#[derive(Debug, ArgEnum, Copy, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub enum RaidBackend {
+    #[default]
+    #[clap(name = "mdadm")]
+    #[serde(rename = "mdadm")]
+    Mdadm,
+
+    #[clap(name = "btrfs")]
+    #[serde(rename = "btrfs")]
+    Btrfs,
+}
+
+#[derive(Debug, ArgEnum, Copy, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub enum FstabReference {
+    #[default]
+    #[clap(name = "uuid")]
+    #[serde(rename = "uuid")]
+    Uuid,
+
+    #[clap(name = "partuuid")]
+    #[serde(rename = "partuuid")]
+    Partuuid,
+
+    #[clap(name = "label")]
+    #[serde(rename = "label")]
+    Label,
+}
+
+#[derive(Debug, ArgEnum, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum WipeMethod {
+    #[clap(name = "signatures")]
+    #[serde(rename = "signatures")]
+    Signatures,
+
+    #[clap(name = "discard")]
+    #[serde(rename = "discard")]
+    Discard,
+
+    #[clap(name = "zero")]
+    #[serde(rename = "zero")]
+    Zero,
 }
 
 #[derive(Debug, Subcommand)]
@@ -226,7 +761,7 @@ pub enum UsersSubcommand {
     #[clap(name="root-password", aliases=&["root-pass", "rootPass"])]
     RootPass {
         /// The password to set. NOTE: Takes hashed password, use `openssl passwd -1 <password>` to generate the hash.
-        password: String,
+        password: Secret,
     },
 }
 
@@ -242,57 +777,218 @@ pub struct NewUserArgs {
     /// The password to set. NOTE: Takes hashed password, use `openssl passwd -6 <password>` to generate the hash.
     /// When not providing a password openssl jumps into an interactive masked input mode allowing you to hide your password
     /// from the terminal history.
-    pub password: String,
+    pub password: Secret,
 
     /// The shell to use for the user. The current options are bash, csh, fish, tcsh, and zsh.
-    /// If a shell is not specified or unknown, it defaults to fish.
-    pub shell: String,
+    #[clap(arg_enum)]
+    pub shell: Shell,
 }
 
-#[derive(Debug, ArgEnum, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, ArgEnum, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Shell {
+    #[clap(name = "bash")]
+    #[serde(rename = "bash")]
+    Bash,
+
+    #[clap(name = "csh")]
+    #[serde(rename = "csh")]
+    Csh,
+
+    #[clap(name = "fish")]
+    #[serde(rename = "fish")]
+    Fish,
+
+    #[clap(name = "tcsh")]
+    #[serde(rename = "tcsh")]
+    Tcsh,
+
+    #[clap(name = "zsh")]
+    #[serde(rename = "zsh")]
+    Zsh,
+}
+
+#[derive(Debug, ArgEnum, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Serialize)]
 pub enum DesktopSetup {
     #[clap(name = "onyx")]
+    #[serde(rename = "onyx")]
     Onyx,
 
     #[clap(name = "gnome")]
+    #[serde(rename = "gnome")]
     Gnome,
 
     #[clap(name = "kde", aliases = ["plasma"])]
+    #[serde(rename = "kde")]
     Kde,
 
     #[clap(name = "budgie")]
+    #[serde(rename = "budgie")]
     Budgie,
 
     #[clap(name = "cinnamon")]
+    #[serde(rename = "cinnamon")]
     Cinnamon,
 
     #[clap(name = "mate")]
+    #[serde(rename = "mate")]
     Mate,
 
     #[clap(name = "xfce")]
+    #[serde(rename = "xfce")]
     Xfce,
 
     #[clap(name = "enlightenment")]
+    #[serde(rename = "enlightenment")]
     Enlightenment,
 
     #[clap(name = "lxqt")]
+    #[serde(rename = "lxqt")]
     Lxqt,
 
     #[clap(name = "sway")]
+    #[serde(rename = "sway")]
     Sway,
 
     #[clap(name = "i3")]
+    #[serde(rename = "i3")]
     I3,
 
     #[clap(name = "herbstluftwm")]
+    #[serde(rename = "herbstluftwm")]
     Herbstluftwm,
 
     #[clap(name = "awesome")]
+    #[serde(rename = "awesome")]
     Awesome,
 
     #[clap(name = "bspwm")]
+    #[serde(rename = "bspwm")]
     Bspwm,
 
-    #[clap(name = "None/DIY")]
+    #[clap(name = "None/DIY", aliases = ["none"])]
+    #[serde(rename = "none/diy")]
     None,
 }
+
+/// Config files name desktops in any case, like "KDE" or "Onyx"
+impl<'de> Deserialize<'de> for DesktopSetup {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        <DesktopSetup as ArgEnum>::from_str(&name, true)
+            .map_err(|_| serde::de::Error::custom(format!("unknown desktop {name:?}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn desktop_aliases() {
+        let desktop: DesktopSetup = serde_json::from_str("\"plasma\"").unwrap();
+        assert_eq!(desktop, DesktopSetup::Kde);
+        let desktop: DesktopSetup = serde_json::from_str("\"None/DIY\"").unwrap();
+        assert_eq!(desktop, DesktopSetup::None);
+        let desktop: DesktopSetup = serde_json::from_str("\"KDE\"").unwrap();
+        assert_eq!(desktop, DesktopSetup::Kde);
+        let desktop: DesktopSetup = serde_json::from_str("\"Onyx\"").unwrap();
+        assert_eq!(desktop, DesktopSetup::Onyx);
+        let kernel: Kernel = serde_json::from_str("\"lts\"").unwrap();
+        assert_eq!(kernel, Kernel::LinuxLts);
+    }
+
+    #[test]
+    fn parses_partition_specs() {
+        let partition: Partition = "/:/dev/sda2:btrfs:noatime,compress=zstd,label=root,subvolume=@=/,subvolume=@home=/home"
+            .parse()
+            .unwrap();
+        assert_eq!(partition.blockdevice, "/dev/sda2");
+        assert_eq!(partition.mount_options, ["noatime", "compress=zstd"]);
+        assert_eq!(partition.label.as_deref(), Some("root"));
+        assert_eq!(partition.subvolumes[1].name, "@home");
+        assert_eq!(partition.subvolumes[1].mountpoint, "/home");
+        let partition: Partition = "/boot:/dev/sda1:vfat".parse().unwrap();
+        assert!(partition.mount_options.is_empty());
+        let partition: Partition = "/mnt/home:/dev/sdb2:btrfs".parse().unwrap();
+        assert_eq!(partition.mountpoint, "/home");
+        let partition: Partition = "/mnt:/dev/sda2:ext4".parse().unwrap();
+        assert_eq!(partition.mountpoint, "/");
+        let partition: Partition = "/mntdata:/dev/sda3:ext4".parse().unwrap();
+        assert_eq!(partition.mountpoint, "/mntdata");
+    }
+
+    #[test]
+    fn rejects_malformed_partition_specs() {
+        assert!("/:/dev/sda2".parse::<Partition>().is_err());
+        assert!("/:/dev/sda2:btrfs:subvolume=@"
+            .parse::<Partition>()
+            .is_err());
+        let parse = |specs: &[&str]| -> Vec<Partition> {
+            specs.iter().map(|s| s.parse().unwrap()).collect()
+        };
+        let check_spec = |spec: &str| parse(&[spec])[0].check_spec();
+        assert_eq!(check_spec("/:/dev/sda2:ext4"), Ok(()));
+        assert!(check_spec("home:/dev/sda2:ext4")
+            .unwrap_err()
+            .contains("absolute"));
+        assert!(check_spec("/:/dev/sda2:ext5")
+            .unwrap_err()
+            .contains("Unknown"));
+        assert!(check_spec("/:/dev/sda2:ext4:subvolume=@=/")
+            .unwrap_err()
+            .contains("not btrfs"));
+        assert!(parse_disk("/data:/dev/sdb:noformat")
+            .unwrap_err()
+            .contains("from scratch"));
+        assert!(parse_disk("none:/dev/sdb:lvm")
+            .unwrap_err()
+            .contains("from scratch"));
+        let clashes = |specs: &[&str]| check_clashes(&parse(specs), None);
+        assert_eq!(
+            clashes(&["/:/dev/sda2:ext4", "/home:/dev/sda3:ext4"]),
+            Ok(())
+        );
+        assert!(clashes(&["/:/dev/sda2:ext4", "/:/dev/sda3:ext4"])
+            .unwrap_err()
+            .contains("more than once"));
+        assert!(clashes(&["/:/dev/sda2:ext4", "/home:/dev/sda2:ext4"])
+            .unwrap_err()
+            .contains("more than once"));
+        assert!(clashes(&[
+            "/:/dev/sda2:btrfs:subvolume=@home=/home",
+            "/home:/dev/sda3:ext4"
+        ])
+        .is_err());
+        let lvm = Lvm::root_only("ext4");
+        assert_eq!(check_spec("none:/dev/sda2:lvm"), Ok(()));
+        assert!(check_spec("none:/dev/sda2:lvm:label=pv").is_err());
+        assert_eq!(
+            check_clashes(&parse(&["none:/dev/sda2:lvm"]), Some(&lvm)),
+            Ok(())
+        );
+        assert!(check_clashes(
+            &parse(&["none:/dev/sda2:lvm", "/:/dev/sda3:ext4"]),
+            Some(&lvm)
+        )
+        .unwrap_err()
+        .contains("more than once"));
+        assert!(clashes(&["none:/dev/sda2:lvm"])
+            .unwrap_err()
+            .contains("no logical volumes"));
+        let validate = |spec: &str| parse(&[spec])[0].validate();
+        assert!(validate("/:/dev/jade-missing:ext4")
+            .unwrap_err()
+            .contains("doesn't exist"));
+        assert!(validate("/:/dev/null:ext4")
+            .unwrap_err()
+            .contains("not a block device"));
+    }
+
+    #[test]
+    fn unknown_values_are_rejected() {
+        assert!(serde_json::from_str::<DesktopSetup>("\"unity\"").is_err());
+        assert!(serde_json::from_str::<Kernel>("\"linux-rt\"").is_err());
+        assert!(serde_json::from_str::<Shell>("\"nu\"").is_err());
+        assert!(serde_json::from_str::<BootloaderType>("\"systemd-boot\"").is_err());
+    }
+}
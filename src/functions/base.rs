@@ -1,25 +1,19 @@
+use crate::args::{BootloaderType, FstabReference, Kernel};
+use crate::functions::{encryption, fstab, initramfs, raid};
 use crate::internal::config::get_packages;
 use crate::internal::exec::*;
 use crate::internal::files::append_file;
+use crate::internal::state;
 use crate::internal::*;
-use log::warn;
 use std::path::PathBuf;
 
-pub fn install_base_packages(kernel: String) {
+pub fn install_base_packages(kernel: Kernel) {
     std::fs::create_dir_all("/mnt/etc").unwrap();
-    let kernel_to_install = if kernel.is_empty() {
-        "linux"
-    } else {
-        match kernel.as_str() {
-            "linux" => "linux",
-            "linux-lts" => "linux-lts",
-            "linux-zen" => "linux-zen",
-            "linux-hardened" => "linux-hardened",
-            _ => {
-                warn!("Unknown kernel: {}, using default instead", kernel);
-                "linux"
-            }
-        }
+    let kernel_to_install = match kernel {
+        Kernel::Linux => "linux",
+        Kernel::LinuxLts => "linux-lts",
+        Kernel::LinuxZen => "linux-zen",
+        Kernel::LinuxHardened => "linux-hardened",
     };
     let kernel_headers_to_install = format!("{kernel_to_install}-headers");
     let pkg_list = get_packages();
@@ -45,19 +39,71 @@ pub fn install_base_packages(kernel: String) {
         ),
         "Enable CUPS",
     );
+    initramfs::configure();
 }
 
-pub fn genfstab() {
-    exec_eval(
-        exec(
-            "bash",
-            vec![
-                String::from("-c"),
-                String::from("genfstab -U /mnt >> /mnt/etc/fstab"),
-            ],
-        ),
-        "Generate fstab",
-    );
+/// Writes fstab from what partitioning mounted and crypttab from what it encrypted,
+/// running this again doesn't add anything twice
+pub fn genfstab(reference: FstabReference) {
+    let state = state::load();
+    let mounts = if state.mounts.is_empty() {
+        log::info!("jade didn't mount anything, generating fstab from what is mounted below /mnt");
+        fstab::mounted()
+    } else {
+        state.mounts
+    };
+    if mounts.is_empty() {
+        crash(
+            "Nothing is mounted below /mnt, partition the install destination first",
+            1,
+        );
+    }
+    fstab::write(&mounts, &state.swaps, reference);
+    if !state.luks_volumes.is_empty() {
+        let existing = std::fs::read_to_string("/mnt/etc/crypttab").unwrap_or_default();
+        let crypttab = encryption::crypttab(&state.luks_volumes);
+        let missing: Vec<&str> = crypttab
+            .lines()
+            .filter(|line| !existing.lines().any(|l| l == *line))
+            .collect();
+        if !missing.is_empty() {
+            files_eval(
+                append_file("/mnt/etc/crypttab", &missing.join("\n")),
+                "Add encrypted volumes to crypttab",
+            );
+        }
+    }
+}
+
+/// Passes the kernel parameters the storage setup needs to GRUB and lets it unlock
+/// an encrypted /boot, this has to happen before grub-install
+fn configure_grub_storage() {
+    let state = state::load();
+    let params = initramfs::kernel_params(&state);
+    if !params.is_empty() {
+        files_eval(
+            append_file(
+                "/mnt/etc/default/grub",
+                &format!(
+                    "GRUB_CMDLINE_LINUX=\"$GRUB_CMDLINE_LINUX {}\"",
+                    params.join(" ")
+                ),
+            ),
+            "add storage kernel parameters to grub",
+        );
+    }
+    if state.dual_boot {
+        files_eval(
+            append_file("/mnt/etc/default/grub", "GRUB_DISABLE_OS_PROBER=false"),
+            "enable os-prober to find the other OS",
+        );
+    }
+    if state.grub_cryptodisk {
+        files_eval(
+            append_file("/mnt/etc/default/grub", "GRUB_ENABLE_CRYPTODISK=y"),
+            "enable grub cryptodisk",
+        );
+    }
 }
 
 pub fn install_bootloader_grub_efi(efidir: PathBuf) {
@@ -73,18 +119,22 @@ pub fn install_bootloader_grub_efi(efidir: PathBuf) {
     if !std::path::Path::new(&format!("/mnt{efi_str}")).exists() {
         crash(format!("The efidir {efidir:?} doesn't exist"), 1);
     }
-    exec_eval(
-        exec_chroot(
-            "grub-install",
-            vec![
-                String::from("--target=x86_64-efi"),
-                format!("--efi-directory={}", efi_str),
-                String::from("--bootloader-id=crystal"),
-                String::from("--removable"),
-            ],
-        ),
-        "install grub as efi with --removable",
-    );
+    configure_grub_storage();
+    // the fallback bootloader of a shared EFI system partition belongs to the other OS
+    if !state::load().dual_boot {
+        exec_eval(
+            exec_chroot(
+                "grub-install",
+                vec![
+                    String::from("--target=x86_64-efi"),
+                    format!("--efi-directory={}", efi_str),
+                    String::from("--bootloader-id=crystal"),
+                    String::from("--removable"),
+                ],
+            ),
+            "install grub as efi with --removable",
+        );
+    }
     exec_eval(
         exec_chroot(
             "grub-install",
@@ -96,6 +146,8 @@ pub fn install_bootloader_grub_efi(efidir: PathBuf) {
         ),
         "install grub as efi without --removable",
     );
+    // the removable path lets the firmware boot the copies on other RAID disks too
+    raid::mirror_esp(&format!("/mnt{efi_str}"), &state::load().mirror_esps);
     files_eval(
         append_file(
             "/mnt/etc/default/grub",
@@ -123,6 +175,7 @@ pub fn install_bootloader_grub_legacy(device: PathBuf) {
         crash(format!("The device {device:?} does not exist"), 1);
     }
     let device = device.to_string_lossy().to_string();
+    configure_grub_storage();
     exec_eval(
         exec_chroot(
             "grub-install",
@@ -130,6 +183,15 @@ pub fn install_bootloader_grub_legacy(device: PathBuf) {
         ),
         "install grub as legacy",
     );
+    for disk in state::load().mirror_disks {
+        exec_eval(
+            exec_chroot(
+                "grub-install",
+                vec![String::from("--target=i386-pc"), disk.clone()],
+            ),
+            format!("install grub as legacy on {disk}").as_str(),
+        );
+    }
     files_eval(
         append_file(
             "/mnt/etc/default/grub",
@@ -206,16 +268,26 @@ pub fn install_bootloader_refind(efidir: PathBuf, default: bool, device: PathBuf
     };
 }
 
-pub fn setup_timeshift(bootloader: String) {
+pub fn setup_timeshift(bootloader: BootloaderType) {
+    // timeshift takes btrfs snapshots where it can and falls back to rsync otherwise
+    let btrfs = exec_output(
+        "findmnt",
+        vec![
+            String::from("-n"),
+            String::from("-o"),
+            String::from("FSTYPE"),
+            String::from("/mnt"),
+        ],
+    )
+    .is_ok_and(|fstype| fstype.trim() == "btrfs");
     let mut pkgs = vec!["timeshift", "timeshift-autosnap"];
-    if bootloader.contains("grub") {
+    if btrfs && bootloader != BootloaderType::Refind {
         pkgs.push("grub-btrfs");
-    } // else if bootloader == "refind" {
-      //     //
-      // }
+    }
     install(pkgs);
+    let mode = if btrfs { "--btrfs" } else { "--rsync" };
     exec_eval(
-        exec_chroot("timeshift", vec![String::from("--btrfs")]),
+        exec_chroot("timeshift", vec![String::from(mode)]),
         "setup timeshift",
     )
 }
@@ -0,0 +1,638 @@
+use crate::args::WipeMethod;
+use crate::functions::layout::gpt_type;
+use crate::internal::exec::*;
+use crate::internal::*;
+use serde::Serialize;
+use std::path::Path;
+
+/// Returns the kernel name of a block device, e.g. `sda` for `/dev/sda`
+pub fn kernel_name(device: &Path) -> String {
+    let device = device
+        .canonicalize()
+        .unwrap_or_else(|_| device.to_path_buf());
+    device
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Returns the size of a disk in MiB, as reported by sysfs
+pub fn disk_size_mib(device: &Path) -> u64 {
+    let name = kernel_name(device);
+    let size = std::fs::read_to_string(format!("/sys/class/block/{name}/size"));
+    match size.map(|s| s.trim().parse::<u64>()) {
+        // sysfs always counts in 512 byte sectors
+        Ok(Ok(sectors)) => sectors * 512 / 1024 / 1024,
+        _ => crash(format!("Read size of {device:?} from sysfs"), 1),
+    }
+}
+
+/// Returns the block device of partition number `number` on `device`.
+/// The partition is looked up in sysfs, so this works for any kind of disk
+/// no matter if the kernel names its partitions `sda1`, `nvme0n1p1` or `loop0p1`.
+pub fn partition_path(device: &Path, number: usize) -> String {
+    let name = kernel_name(device);
+    let entries = match std::fs::read_dir(format!("/sys/class/block/{name}")) {
+        Ok(entries) => entries,
+        Err(e) => crash(
+            format!("Read partitions of {device:?} from sysfs  ERROR: {e}"),
+            1,
+        ),
+    };
+    for entry in entries.flatten() {
+        let partition = std::fs::read_to_string(entry.path().join("partition"));
+        if partition.is_ok_and(|p| p.trim() == number.to_string()) {
+            return format!("/dev/{}", entry.file_name().to_string_lossy());
+        }
+    }
+    crash(format!("Partition {number} of {device:?} doesn't exist"), 1);
+}
+
+/// Returns the number and block device of the partition starting at `start_mib` on `device`
+pub fn partition_at(device: &Path, start_mib: u64) -> (usize, String) {
+    let name = kernel_name(device);
+    for child in partition_names(&name) {
+        // sysfs always counts in 512 byte sectors
+        if sysfs_number(&name, &format!("{child}/start")) == start_mib * 2048 {
+            let number = sysfs_number(&name, &format!("{child}/partition"));
+            return (number as usize, format!("/dev/{child}"));
+        }
+    }
+    crash(
+        format!("No partition of {device:?} starts at {start_mib}MiB"),
+        1,
+    );
+}
+
+/// Whether `device` is an LVM logical volume
+pub fn is_lvm_volume(device: &Path) -> bool {
+    sysfs_attr(&kernel_name(device), "dm/uuid").is_some_and(|uuid| uuid.starts_with("LVM-"))
+}
+
+/// How much of the start and end of a disk gets zeroed, enough for a LUKS2 header,
+/// the backup GPT and mdadm superblocks at the end
+const WIPE_ZERO_MIB: u64 = 16;
+
+/// Removes all signatures from `device` and its partitions so nothing of an earlier install
+/// confuses blkid later, then discards or zeroes the disk if `method` says so
+pub fn wipe(device: &Path, method: WipeMethod) {
+    let device_str = device.to_string_lossy().to_string();
+    log::info!("Wiping {device_str}");
+    // new partitions often start where the old ones did, so their signatures go first
+    for child in partition_names(&kernel_name(device)) {
+        exec_eval(
+            exec(
+                "wipefs",
+                vec![String::from("--all"), format!("/dev/{child}")],
+            ),
+            format!("Wipe signatures of /dev/{child}").as_str(),
+        );
+    }
+    exec_eval(
+        exec("wipefs", vec![String::from("--all"), device_str.clone()]),
+        format!("Wipe signatures of {device_str}").as_str(),
+    );
+    match method {
+        WipeMethod::Signatures => {}
+        WipeMethod::Discard
+            if sysfs_number(&kernel_name(device), "queue/discard_max_bytes") > 0 =>
+        {
+            exec_eval(
+                exec(
+                    "blkdiscard",
+                    vec![String::from("--force"), device_str.clone()],
+                ),
+                format!("Discard all blocks of {device_str}").as_str(),
+            );
+        }
+        WipeMethod::Discard | WipeMethod::Zero => {
+            if method == WipeMethod::Discard {
+                log::warn!("{device_str} doesn't support discarding, zeroing it instead");
+            }
+            let size = disk_size_mib(device);
+            for seek in [0, size.saturating_sub(WIPE_ZERO_MIB)] {
+                exec_eval(
+                    exec(
+                        "dd",
+                        vec![
+                            String::from("if=/dev/zero"),
+                            format!("of={device_str}"),
+                            String::from("bs=1M"),
+                            format!("count={WIPE_ZERO_MIB}"),
+                            format!("seek={seek}"),
+                            String::from("conv=fsync"),
+                        ],
+                    ),
+                    format!("Zero {device_str} at {seek}MiB").as_str(),
+                );
+            }
+        }
+    }
+}
+
+/// Whether `partition` is a partition of `disk`
+pub fn is_partition_of(partition: &Path, disk: &Path) -> bool {
+    partition_names(&kernel_name(disk)).contains(&kernel_name(partition))
+}
+
+/// Lists the EFI system partitions of all disks but `exclude`, recognized by their GPT or
+/// MBR partition type
+pub fn find_esps(exclude: &[&Path]) -> Vec<String> {
+    let excluded: Vec<String> = exclude.iter().map(|disk| kernel_name(disk)).collect();
+    list_disks()
+        .into_iter()
+        .filter(|disk| !excluded.contains(&disk.name))
+        .flat_map(|disk| disk.partitions)
+        .filter(|partition| {
+            udev_property(&partition.name, "ID_PART_ENTRY_TYPE")
+                .is_some_and(|t| t.eq_ignore_ascii_case(gpt_type::ESP) || t == "0xef")
+        })
+        .map(|partition| partition.path)
+        .collect()
+}
+
+/// Waits until udev has created the device nodes of new partitions
+pub fn settle() {
+    exec_eval(
+        exec("udevadm", vec![String::from("settle")]),
+        "Wait for udev to create partition device nodes",
+    );
+}
+
+/// Lists the kernel names of the partitions of a disk
+fn partition_names(name: &str) -> Vec<String> {
+    std::fs::read_dir(format!("/sys/class/block/{name}"))
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .filter(|child| sysfs_attr(name, &format!("{child}/partition")).is_some())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Lists the devices stacked directly on top of a block device, like LVM, LUKS or RAID
+fn holders(name: &str) -> Vec<String> {
+    std::fs::read_dir(format!("/sys/class/block/{name}/holders"))
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Returns the kernel name of the block device behind a path in /proc/mounts or /proc/swaps
+fn source_name(source: &str) -> Option<String> {
+    if !source.starts_with("/dev/") {
+        return None;
+    }
+    Some(kernel_name(Path::new(source)))
+}
+
+/// Lists the disk, its partitions and whatever is stacked on top of them, every layer after
+/// the ones below it
+fn device_tree(name: &str) -> Vec<String> {
+    let mut tree = vec![name.to_string()];
+    tree.extend(partition_names(name));
+    let mut i = 0;
+    while i < tree.len() {
+        for holder in holders(&tree[i]) {
+            if !tree.contains(&holder) {
+                tree.push(holder);
+            }
+        }
+        i += 1;
+    }
+    tree
+}
+
+/// The kernel names of the devices that are used as swap
+fn active_swaps() -> Vec<String> {
+    let swaps = std::fs::read_to_string("/proc/swaps").unwrap_or_default();
+    swaps
+        .lines()
+        .skip(1)
+        .filter_map(|l| l.split_whitespace().next())
+        .filter_map(source_name)
+        .collect()
+}
+
+/// Turns off swap on `device` and deactivates the LVM, LUKS and RAID devices stacked on top
+/// of it, top first. Its filesystems have to be unmounted already.
+pub fn release(device: &Path) {
+    let swaps = active_swaps();
+    for name in device_tree(&kernel_name(device)).iter().rev() {
+        let path = format!("/dev/{name}");
+        if swaps.contains(name) {
+            exec_eval(
+                exec("swapoff", vec![path.clone()]),
+                format!("Turn off swap on {path}").as_str(),
+            );
+        }
+        if name.starts_with("dm-") {
+            exec_eval(
+                exec("dmsetup", vec![String::from("remove"), path.clone()]),
+                format!("Deactivate {path}").as_str(),
+            );
+        } else if name.starts_with("md") {
+            exec_eval(
+                exec("mdadm", vec![String::from("--stop"), path.clone()]),
+                format!("Stop {path}").as_str(),
+            );
+        }
+    }
+}
+
+/// Checks that nothing on `device` is in use, so wiping it can't break the running system
+pub fn check_safe_to_wipe(device: &Path) -> Result<(), String> {
+    let name = kernel_name(device);
+    let mut parts = vec![name.clone()];
+    parts.extend(partition_names(&name));
+
+    // everything that could be mounted or used as swap: the disk, its partitions and
+    // whatever is stacked on top of them
+    let tree = device_tree(&name);
+
+    let mounts = std::fs::read_to_string("/proc/mounts").unwrap_or_default();
+    for line in mounts.lines() {
+        let mut fields = line.split_whitespace();
+        let (source, target) = match (fields.next(), fields.next()) {
+            (Some(source), Some(target)) => (source, target),
+            _ => continue,
+        };
+        if source_name(source).is_some_and(|source| tree.contains(&source)) {
+            if target == "/" || target.starts_with("/run/archiso") {
+                return Err(format!(
+                    "it backs the running live system ({source} is mounted at {target})"
+                ));
+            }
+            return Err(format!(
+                "it has mounted partitions ({source} is mounted at {target}), unmount them first"
+            ));
+        }
+    }
+
+    if let Some(source) = active_swaps().iter().find(|source| tree.contains(source)) {
+        return Err(format!(
+            "it is used as swap (/dev/{source}), run swapoff first"
+        ));
+    }
+
+    for part in &parts {
+        if let Some(holder) = holders(part).first() {
+            let kind = if holder.starts_with("md") {
+                "a RAID array"
+            } else {
+                "LVM or an encrypted volume"
+            };
+            return Err(format!(
+                "/dev/{part} is part of {kind} (/dev/{holder}), deactivate it first"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Describes what is on `device` according to its signature, e.g.
+/// `ext4 filesystem labeled "home" (20.0GiB, 3.1GiB used)`, `None` if it looks empty
+pub fn existing_data(device: &Path) -> Option<String> {
+    let probe = exec_output(
+        "blkid",
+        vec![
+            String::from("--probe"),
+            String::from("--output"),
+            String::from("export"),
+            device.to_string_lossy().to_string(),
+        ],
+    )
+    .unwrap_or_default();
+    let mut description = describe_signature(&probe)?;
+    let size = sysfs_number(&kernel_name(device), "size") * 512;
+    let filesystem = probe_value(&probe, "TYPE").unwrap_or_default();
+    match df_bytes(device, filesystem, "used") {
+        Some(used) => description.push_str(&format!(
+            " ({}, {} used)",
+            human_size(size),
+            human_size(used)
+        )),
+        None => description.push_str(&format!(" ({})", human_size(size))),
+    }
+    Some(description)
+}
+
+/// Reads a value of `blkid --output export`
+pub fn probe_value<'a>(probe: &'a str, key: &str) -> Option<&'a str> {
+    probe
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+}
+
+fn describe_signature(probe: &str) -> Option<String> {
+    let kind = match probe_value(probe, "TYPE")? {
+        "crypto_LUKS" => String::from("LUKS encrypted volume"),
+        "BitLocker" => String::from("BitLocker encrypted volume"),
+        "LVM2_member" => String::from("LVM physical volume"),
+        "linux_raid_member" => String::from("RAID member"),
+        "swap" => String::from("swap"),
+        filesystem => format!("{filesystem} filesystem"),
+    };
+    Some(match probe_value(probe, "LABEL") {
+        Some(label) => format!("{kind} labeled {label:?}"),
+        None => kind,
+    })
+}
+
+/// Mount options that keep a read-only mount from replaying the journal, so looking at the
+/// used space doesn't touch a filesystem that might not get formatted after all
+fn probe_mount_options(filesystem: &str) -> Option<&'static str> {
+    match filesystem {
+        "ext2" | "ext3" | "ext4" => Some("ro,noload"),
+        "xfs" | "f2fs" => Some("ro,norecovery"),
+        "btrfs" => Some("ro,rescue=nologreplay"),
+        "vfat" | "exfat" | "ntfs" => Some("ro"),
+        _ => None,
+    }
+}
+
+/// How much space is free on the filesystem on `device`, found by mounting it read-only for a
+/// moment
+pub fn free_bytes(device: &Path, filesystem: &str) -> Option<u64> {
+    df_bytes(device, filesystem, "avail")
+}
+
+/// Reads a column of `df` like `used` or `avail` for the filesystem on `device`, by mounting
+/// it read-only for a moment
+fn df_bytes(device: &Path, filesystem: &str, column: &str) -> Option<u64> {
+    let options = probe_mount_options(filesystem)?;
+    let mountpoint = "/tmp/jade-probe";
+    std::fs::create_dir_all(mountpoint).ok()?;
+    let mounted = exec(
+        "mount",
+        vec![
+            String::from("-o"),
+            String::from(options),
+            device.to_string_lossy().to_string(),
+            String::from(mountpoint),
+        ],
+    )
+    .is_ok_and(|status| status.success());
+    let bytes = mounted
+        .then(|| {
+            exec_output(
+                "df",
+                vec![
+                    String::from("--block-size=1"),
+                    format!("--output={column}"),
+                    String::from(mountpoint),
+                ],
+            )
+            .ok()
+        })
+        .flatten()
+        .and_then(|output| output.lines().last()?.trim().parse().ok());
+    if mounted {
+        exec_eval(
+            exec("umount", vec![String::from(mountpoint)]),
+            format!("unmount {mountpoint}").as_str(),
+        );
+    }
+    std::fs::remove_dir(mountpoint).ok();
+    bytes
+}
+
+/// A disk that jade could install to
+#[derive(Debug, Serialize)]
+pub struct Disk {
+    pub name: String,
+    pub path: String,
+    pub model: Option<String>,
+    /// size in bytes
+    pub size: u64,
+    pub logical_sector_size: u64,
+    pub physical_sector_size: u64,
+    pub rotational: bool,
+    pub removable: bool,
+    pub transport: Option<String>,
+    /// `gpt` or `dos`, if the disk has a partition table
+    pub partition_table: Option<String>,
+    pub partitions: Vec<DiskPartition>,
+}
+
+/// A partition on a `Disk`
+#[derive(Debug, Serialize)]
+pub struct DiskPartition {
+    pub name: String,
+    pub path: String,
+    pub number: usize,
+    /// size in bytes
+    pub size: u64,
+    pub filesystem: Option<String>,
+    pub label: Option<String>,
+}
+
+/// Reads a sysfs attribute of a block device, trimmed
+fn sysfs_attr(name: &str, attr: &str) -> Option<String> {
+    std::fs::read_to_string(format!("/sys/class/block/{name}/{attr}"))
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+fn sysfs_number(name: &str, attr: &str) -> u64 {
+    sysfs_attr(name, attr)
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Reads a property udev stored about a block device, e.g. `ID_FS_TYPE`
+fn udev_property(name: &str, key: &str) -> Option<String> {
+    let dev = sysfs_attr(name, "dev")?;
+    let data = std::fs::read_to_string(format!("/run/udev/data/b{dev}")).ok()?;
+    data.lines()
+        .filter_map(|line| line.strip_prefix("E:"))
+        .filter_map(|line| line.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v.to_string())
+}
+
+/// Works out how a disk is connected from its name and its path in sysfs
+fn transport(name: &str, sysfs_path: &str) -> Option<String> {
+    let transport = if name.starts_with("nvme") {
+        "nvme"
+    } else if name.starts_with("loop") {
+        "loop"
+    } else if sysfs_path.contains("/usb") {
+        "usb"
+    } else if sysfs_path.contains("/virtio") {
+        "virtio"
+    } else if sysfs_path.contains("/mmc") {
+        "mmc"
+    } else if sysfs_path.contains("/ata") {
+        "sata"
+    } else if sysfs_path.contains("/host") {
+        "scsi"
+    } else {
+        return None;
+    };
+    Some(String::from(transport))
+}
+
+/// Lists all disks in the system, skipping empty, read-only and RAM backed devices
+pub fn list_disks() -> Vec<Disk> {
+    let mut names: Vec<String> = match std::fs::read_dir("/sys/block") {
+        Ok(entries) => entries
+            .flatten()
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .collect(),
+        Err(e) => crash(format!("Read disks from /sys/block  ERROR: {e}"), 1),
+    };
+    names.sort();
+    names.retain(|name| {
+        !name.starts_with("ram")
+            && !name.starts_with("zram")
+            && sysfs_number(name, "size") > 0
+            && sysfs_number(name, "ro") == 0
+    });
+    names.iter().map(|name| read_disk(name)).collect()
+}
+
+fn read_disk(name: &str) -> Disk {
+    let sysfs_path = std::fs::canonicalize(format!("/sys/block/{name}"))
+        .map(|path| path.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut partitions: Vec<DiskPartition> = partition_names(name)
+        .into_iter()
+        .map(|child| DiskPartition {
+            path: format!("/dev/{child}"),
+            number: sysfs_number(&child, "partition") as usize,
+            size: sysfs_number(&child, "size") * 512,
+            filesystem: udev_property(&child, "ID_FS_TYPE"),
+            label: udev_property(&child, "ID_FS_LABEL"),
+            name: child,
+        })
+        .collect();
+    partitions.sort_by_key(|partition| partition.number);
+    Disk {
+        name: name.to_string(),
+        path: format!("/dev/{name}"),
+        model: sysfs_attr(name, "device/model"),
+        size: sysfs_number(name, "size") * 512,
+        logical_sector_size: sysfs_number(name, "queue/logical_block_size"),
+        physical_sector_size: sysfs_number(name, "queue/physical_block_size"),
+        rotational: sysfs_number(name, "queue/rotational") == 1,
+        removable: sysfs_number(name, "removable") == 1,
+        transport: transport(name, &sysfs_path),
+        partition_table: udev_property(name, "ID_PART_TABLE_TYPE"),
+        partitions,
+    }
+}
+
+/// Formats a size in bytes for humans, e.g. `476.9GiB`
+pub fn human_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes}B")
+    } else {
+        format!("{size:.1}{}", units[unit])
+    }
+}
+
+/// Prints all disks, either for humans or as JSON for frontends
+pub fn print_disks(json: bool) {
+    let disks = list_disks();
+    if json {
+        match serde_json::to_string_pretty(&disks) {
+            Ok(json) => println!("{json}"),
+            Err(e) => crash(format!("Serialize disk list  ERROR: {e}"), 1),
+        }
+        return;
+    }
+    for disk in disks {
+        println!(
+            "{}  {}  {}  {}/{}  {}  {}{}  {}",
+            disk.path,
+            disk.model.as_deref().unwrap_or("unknown model"),
+            human_size(disk.size),
+            disk.logical_sector_size,
+            disk.physical_sector_size,
+            if disk.rotational { "hdd" } else { "ssd" },
+            disk.transport.as_deref().unwrap_or("unknown"),
+            if disk.removable { " removable" } else { "" },
+            disk.partition_table
+                .as_deref()
+                .unwrap_or("no partition table"),
+        );
+        for partition in disk.partitions {
+            println!(
+                "    {}  {}  {}  {}",
+                partition.path,
+                human_size(partition.size),
+                partition.filesystem.as_deref().unwrap_or("-"),
+                partition.label.as_deref().unwrap_or("-"),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_sizes() {
+        assert_eq!(human_size(512), "512B");
+        assert_eq!(human_size(512 * 1024 * 1024), "512.0MiB");
+        assert_eq!(human_size(500107862016), "465.8GiB");
+    }
+
+    #[test]
+    fn describes_signatures() {
+        let ext4 = "DEVNAME=/dev/sda2\nLABEL=home\nUUID=ed1b5760\nTYPE=ext4\nUSAGE=filesystem";
+        assert_eq!(
+            describe_signature(ext4).as_deref(),
+            Some("ext4 filesystem labeled \"home\"")
+        );
+        assert_eq!(
+            describe_signature("DEVNAME=/dev/sda3\nTYPE=BitLocker\nUSAGE=crypto").as_deref(),
+            Some("BitLocker encrypted volume")
+        );
+        assert_eq!(describe_signature(""), None);
+    }
+
+    #[test]
+    fn detects_transport() {
+        assert_eq!(
+            transport(
+                "sda",
+                "/sys/devices/pci0000:00/0000:00:17.0/ata1/host0/target0:0:0/0:0:0:0/block/sda"
+            ),
+            Some(String::from("sata"))
+        );
+        assert_eq!(
+            transport(
+                "sdb",
+                "/sys/devices/pci0000:00/0000:00:14.0/usb2/2-1/2-1:1.0/host6/target6:0:0/6:0:0:0/block/sdb"
+            ),
+            Some(String::from("usb"))
+        );
+        assert_eq!(
+            transport(
+                "nvme0n1",
+                "/sys/devices/pci0000:00/0000:00:1d.0/0000:3d:00.0/nvme/nvme0/nvme0n1"
+            ),
+            Some(String::from("nvme"))
+        );
+        assert_eq!(transport("md0", "/sys/devices/virtual/block/md0"), None);
+    }
+}
@@ -0,0 +1,308 @@
+use crate::functions::disks;
+use crate::functions::layout::{Layout, Shrink, Size};
+use crate::internal::exec::*;
+use crate::internal::*;
+use std::path::Path;
+
+/// Where a btrfs partition gets mounted to shrink it
+const SHRINK_MOUNTPOINT: &str = "/tmp/jade-shrink";
+/// Space GRUB needs on an EFI system partition that is shared with another OS
+const ESP_MIN_FREE_MIB: u64 = 32;
+
+/// A partition or stretch of free space on a disk, as printed by `parted -m`
+#[derive(Debug, PartialEq)]
+pub struct Region {
+    /// Partition number, meaningless for free space
+    pub number: usize,
+    /// Start in MiB
+    pub start: f64,
+    /// End in MiB
+    pub end: f64,
+    pub free: bool,
+    /// Partition flags, e.g. `boot, esp`
+    pub flags: String,
+}
+
+/// The partition table of a disk
+#[derive(Debug, PartialEq)]
+pub struct PartitionTable {
+    /// `gpt`, `msdos` or `unknown` for a disk without partition table
+    pub label: String,
+    pub regions: Vec<Region>,
+}
+
+impl PartitionTable {
+    pub fn partitions(&self) -> impl Iterator<Item = &Region> {
+        self.regions.iter().filter(|r| !r.free)
+    }
+
+    /// The biggest stretch of free space, in whole MiB. The last MiB of it is left alone
+    /// in case it runs up to the backup GPT header.
+    pub fn largest_free_space(&self) -> Option<(u64, u64)> {
+        self.regions
+            .iter()
+            .filter(|r| r.free)
+            .map(|r| {
+                (
+                    r.start.ceil() as u64,
+                    (r.end.floor() as u64).saturating_sub(1),
+                )
+            })
+            .filter(|(start, end)| end > start)
+            .max_by_key(|(start, end)| end - start)
+    }
+}
+
+fn parse_mib(value: &str) -> Option<f64> {
+    value.trim().trim_end_matches("MiB").parse().ok()
+}
+
+/// Parses `parted -m <device> unit MiB print free`
+fn parse_parted(output: &str) -> Option<PartitionTable> {
+    let mut lines = output.lines().map(|l| l.trim().trim_end_matches(';'));
+    let disk = lines.find(|line| line.starts_with('/'))?;
+    let label = disk.split(':').nth(5)?.to_string();
+    let regions = lines
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(':').collect();
+            Some(Region {
+                number: fields.first()?.parse().ok()?,
+                start: parse_mib(fields.get(1)?)?,
+                end: parse_mib(fields.get(2)?)?,
+                free: fields.get(4) == Some(&"free"),
+                flags: fields.get(6).unwrap_or(&"").to_string(),
+            })
+        })
+        .collect();
+    Some(PartitionTable { label, regions })
+}
+
+/// Reads the partition table of `device`
+pub fn read_table(device: &Path) -> PartitionTable {
+    let output = exec_output(
+        "parted",
+        vec![
+            String::from("-m"),
+            String::from("-s"),
+            device.to_string_lossy().to_string(),
+            String::from("unit"),
+            String::from("MiB"),
+            String::from("print"),
+            String::from("free"),
+        ],
+    );
+    match output.ok().as_deref().and_then(parse_parted) {
+        Some(table) => table,
+        None => crash(format!("Read the partition table of {device:?}"), 1),
+    }
+}
+
+/// Checks the parts of `layout` that can't be installed next to another OS on the same disk
+pub fn check_layout(layout: &Layout) -> Result<(), String> {
+    if layout.raid.is_some() {
+        return Err(String::from(
+            "RAID needs whole disks, it can't be installed alongside another OS",
+        ));
+    }
+    if !layout.disks.is_empty() {
+        return Err(String::from(
+            "Other disks can only be partitioned along with a whole install device",
+        ));
+    }
+    if layout.esp.is_some() {
+        return Err(String::from(
+            "Installing alongside already reuses the EFI system partition of the disk",
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the EFI system partition of another OS on `device`, if there is one
+pub fn find_esp(device: &Path, table: &PartitionTable) -> Option<String> {
+    table
+        .partitions()
+        .find(|p| p.flags.split(", ").any(|flag| flag == "esp"))
+        .map(|p| disks::partition_path(device, p.number))
+}
+
+/// Checks that an existing EFI system partition is FAT and has room for GRUB
+pub fn check_esp(esp: &str) -> Result<(), String> {
+    let filesystem = filesystem_type(esp);
+    if filesystem != "vfat" {
+        return Err(format!(
+            "{esp} is formatted as {filesystem:?}, an EFI system partition has to be FAT"
+        ));
+    }
+    match disks::free_bytes(Path::new(esp), &filesystem) {
+        Some(free) if free >= ESP_MIN_FREE_MIB * 1024 * 1024 => Ok(()),
+        Some(free) => Err(format!(
+            "{esp} only has {} free, GRUB needs at least {ESP_MIN_FREE_MIB}MiB",
+            disks::human_size(free)
+        )),
+        None => Err(format!("{esp} can't be mounted to check its free space")),
+    }
+}
+
+/// Returns the filesystem on `blockdevice`
+fn filesystem_type(blockdevice: &str) -> String {
+    exec_output(
+        "blkid",
+        vec![
+            String::from("-s"),
+            String::from("TYPE"),
+            String::from("-o"),
+            String::from("value"),
+            String::from(blockdevice),
+        ],
+    )
+    .unwrap_or_default()
+}
+
+/// Shrinks the filesystem on `blockdevice` to `size_mib` MiB
+fn shrink_filesystem(blockdevice: &str, filesystem: &str, size_mib: u64) {
+    match filesystem {
+        "ntfs" => exec_eval(
+            exec_stdin(
+                "ntfsresize",
+                vec![
+                    String::from("--force"),
+                    String::from("--size"),
+                    // ntfsresize counts in powers of 1000, bytes avoid any confusion
+                    (size_mib * 1024 * 1024).to_string(),
+                    String::from(blockdevice),
+                ],
+                "y\n",
+            ),
+            format!("Shrink NTFS on {blockdevice} to {size_mib}MiB").as_str(),
+        ),
+        "ext2" | "ext3" | "ext4" => {
+            exec_eval(
+                exec(
+                    "e2fsck",
+                    vec![
+                        String::from("-f"),
+                        String::from("-y"),
+                        String::from(blockdevice),
+                    ],
+                ),
+                format!("Check {blockdevice} before shrinking it").as_str(),
+            );
+            exec_eval(
+                exec(
+                    "resize2fs",
+                    vec![String::from(blockdevice), format!("{size_mib}M")],
+                ),
+                format!("Shrink {filesystem} on {blockdevice} to {size_mib}MiB").as_str(),
+            );
+        }
+        "btrfs" => {
+            files_eval(
+                files::create_directory(SHRINK_MOUNTPOINT),
+                format!("create {SHRINK_MOUNTPOINT}").as_str(),
+            );
+            exec_eval(
+                exec(
+                    "mount",
+                    vec![String::from(blockdevice), String::from(SHRINK_MOUNTPOINT)],
+                ),
+                format!("mount {blockdevice} at {SHRINK_MOUNTPOINT}").as_str(),
+            );
+            exec_eval(
+                exec(
+                    "btrfs",
+                    vec![
+                        String::from("filesystem"),
+                        String::from("resize"),
+                        format!("{size_mib}m"),
+                        String::from(SHRINK_MOUNTPOINT),
+                    ],
+                ),
+                format!("Shrink btrfs on {blockdevice} to {size_mib}MiB").as_str(),
+            );
+            exec_eval(
+                exec("umount", vec![String::from(SHRINK_MOUNTPOINT)]),
+                format!("unmount {SHRINK_MOUNTPOINT}").as_str(),
+            );
+        }
+        _ => crash(
+            format!(
+                "Can't shrink the {filesystem:?} filesystem on {blockdevice}, only NTFS, ext4 and btrfs can be shrunk"
+            ),
+            1,
+        ),
+    }
+}
+
+/// Shrinks a partition and its filesystem, leaving free space after it
+pub fn shrink_partition(device: &Path, table: &PartitionTable, shrink: &Shrink) {
+    let Size::Mib(by) = shrink.by else {
+        crash("Partitions can only be shrunk by an absolute size", 1);
+    };
+    let number = shrink.partition;
+    let Some(partition) = table.partitions().find(|p| p.number == number) else {
+        crash(format!("Partition {number} of {device:?} doesn't exist"), 1);
+    };
+    let size = (partition.end - partition.start).floor() as u64;
+    if by >= size {
+        crash(
+            format!(
+                "Partition {number} of {device:?} is only {size}MiB, it can't be shrunk by {by}MiB"
+            ),
+            1,
+        );
+    }
+    let new_size = size - by;
+    let blockdevice = disks::partition_path(device, number);
+    shrink_filesystem(&blockdevice, &filesystem_type(&blockdevice), new_size);
+    let device = device.to_string_lossy().to_string();
+    exec_eval(
+        exec_stdin(
+            "sfdisk",
+            vec![
+                String::from("--no-reread"),
+                String::from("-N"),
+                number.to_string(),
+                device.clone(),
+            ],
+            &format!(", {new_size}MiB\n"),
+        ),
+        format!("Shrink partition {number} of {device} to {new_size}MiB").as_str(),
+    );
+    exec_eval(
+        exec("partx", vec![String::from("-u"), device.clone()]),
+        format!("Tell the kernel about the new size of {blockdevice}").as_str(),
+    );
+    disks::settle();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_parted_output() {
+        let output = "BYT;
+/dev/sda:40960MiB:scsi:512:512:gpt:ATA QEMU HARDDISK:;
+1:0.02MiB:1.00MiB:0.98MiB:free;
+1:1.00MiB:101MiB:100MiB:fat32:EFI system partition:boot, esp;
+2:101MiB:20581MiB:20480MiB:ntfs:Basic data partition:msftdata;
+1:20581MiB:40960MiB:20379MiB:free;
+";
+        let table = parse_parted(output).unwrap();
+        assert_eq!(table.label, "gpt");
+        assert_eq!(table.partitions().count(), 2);
+        assert_eq!(table.partitions().next().unwrap().flags, "boot, esp");
+        assert_eq!(table.largest_free_space(), Some((20581, 40959)));
+    }
+
+    #[test]
+    fn checks_alongside_layout() {
+        let mut layout = Layout::default_for(true, false);
+        assert_eq!(check_layout(&layout), Ok(()));
+        layout.disks = vec![crate::functions::layout::DiskLayout {
+            device: "/dev/sdb".into(),
+            partitions: Vec::new(),
+        }];
+        assert!(check_layout(&layout).is_err());
+    }
+}
@@ -0,0 +1,162 @@
+use crate::functions::layout::Encryption;
+use crate::internal::exec::*;
+use crate::internal::state::{self, LuksVolume};
+use crate::internal::*;
+
+/// Returns the UUID of whatever is on `blockdevice`
+pub fn uuid(blockdevice: &str) -> String {
+    match exec_output(
+        "blkid",
+        vec![
+            String::from("-s"),
+            String::from("UUID"),
+            String::from("-o"),
+            String::from("value"),
+            String::from(blockdevice),
+        ],
+    ) {
+        Ok(uuid) if !uuid.is_empty() => uuid,
+        Ok(_) => crash(format!("Read UUID of {blockdevice}  ERROR: no UUID"), 1),
+        Err(e) => crash(format!("Read UUID of {blockdevice}  ERROR: {e}"), 1),
+    }
+}
+
+/// Formats `blockdevice` as LUKS2, enrolls the configured unlock methods and opens it
+/// as /dev/mapper/`name`. `grub_readable` sticks to PBKDF2 since GRUB can't unlock LUKS2
+/// containers using argon2. Returns the path of the unlocked volume.
+pub fn setup_luks(
+    blockdevice: &str,
+    name: &str,
+    encryption: &Encryption,
+    root: bool,
+    grub_readable: bool,
+) -> String {
+    let passphrase = &encryption.passphrase;
+    let mut args = vec![
+        String::from("luksFormat"),
+        String::from("--batch-mode"),
+        String::from("--type"),
+        String::from("luks2"),
+    ];
+    if grub_readable {
+        args.push(String::from("--pbkdf"));
+        args.push(String::from("pbkdf2"));
+    }
+    args.push(String::from("--key-file=-"));
+    args.push(String::from(blockdevice));
+    exec_eval(
+        exec_stdin("cryptsetup", args, passphrase.expose()),
+        format!("Format {blockdevice} as LUKS2").as_str(),
+    );
+    let options = enroll(blockdevice, encryption);
+    exec_eval(
+        exec_stdin(
+            "cryptsetup",
+            vec![
+                String::from("open"),
+                String::from("--key-file=-"),
+                String::from(blockdevice),
+                String::from(name),
+            ],
+            passphrase.expose(),
+        ),
+        format!("Open {blockdevice} as /dev/mapper/{name}").as_str(),
+    );
+    let volume = LuksVolume {
+        uuid: uuid(blockdevice),
+        name: String::from(name),
+        options,
+    };
+    state::update(|state| {
+        if !volume.options.is_empty() {
+            // only the systemd initramfs can unlock with a TPM2 or FIDO2 token
+            state.systemd_initramfs = true;
+        }
+        if root {
+            state.luks_root = Some(volume);
+            state.add_initramfs_hook("encrypt");
+            state.grub_cryptodisk = grub_readable;
+        } else {
+            state.luks_volumes.push(volume);
+        }
+    });
+    format!("/dev/mapper/{name}")
+}
+
+/// Enrolls a TPM2 chip and/or FIDO2 token as additional keys of a LUKS2 container,
+/// returns the crypttab options that make systemd try them at boot
+fn enroll(blockdevice: &str, encryption: &Encryption) -> Vec<String> {
+    let mut options = Vec::new();
+    if let Some(tpm2) = &encryption.tpm2 {
+        let pcrs: Vec<String> = tpm2.pcrs.iter().map(u32::to_string).collect();
+        exec_eval(
+            exec_stdin(
+                "systemd-cryptenroll",
+                vec![
+                    String::from("--unlock-key-file=/dev/stdin"),
+                    format!("--tpm2-device={}", tpm2.device),
+                    format!("--tpm2-pcrs={}", pcrs.join("+")),
+                    String::from(blockdevice),
+                ],
+                encryption.passphrase.expose(),
+            ),
+            format!("Enroll TPM2 for {blockdevice}").as_str(),
+        );
+        options.push(String::from("tpm2-device=auto"));
+        state::update(|state| state.add_package("tpm2-tss"));
+    }
+    if encryption.fido2 {
+        log::info!("Touch your FIDO2 token when it starts blinking");
+        exec_eval(
+            exec_stdin(
+                "systemd-cryptenroll",
+                vec![
+                    String::from("--unlock-key-file=/dev/stdin"),
+                    String::from("--fido2-device=auto"),
+                    String::from(blockdevice),
+                ],
+                encryption.passphrase.expose(),
+            ),
+            format!("Enroll FIDO2 token for {blockdevice}").as_str(),
+        );
+        options.push(String::from("fido2-device=auto"));
+        state::update(|state| state.add_package("libfido2"));
+    }
+    options
+}
+
+/// Lines for /etc/crypttab, unlocking every encrypted volume except the root
+pub fn crypttab(volumes: &[LuksVolume]) -> String {
+    volumes
+        .iter()
+        .map(|volume| {
+            let mut options = vec![String::from("luks")];
+            options.extend(volume.options.iter().cloned());
+            format!(
+                "{} UUID={} none {}",
+                volume.name,
+                volume.uuid,
+                options.join(",")
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Kernel parameters for unlocking the encrypted volume holding the root in the initramfs,
+/// `systemd` is whether the initramfs is built with the systemd hook instead of udev
+pub fn root_kernel_params(root: &LuksVolume, systemd: bool) -> Vec<String> {
+    let mut params = if systemd {
+        vec![format!("rd.luks.name={}={}", root.uuid, root.name)]
+    } else {
+        vec![format!("cryptdevice=UUID={}:{}", root.uuid, root.name)]
+    };
+    if systemd && !root.options.is_empty() {
+        params.push(format!(
+            "rd.luks.options={}={}",
+            root.uuid,
+            root.options.join(",")
+        ));
+    }
+    params
+}
@@ -0,0 +1,271 @@
+use crate::args::FstabReference;
+use crate::functions::disks::probe_value;
+use crate::internal::exec::*;
+use crate::internal::files::write_file;
+use crate::internal::state::Mount;
+use crate::internal::*;
+
+const FSTAB_PATH: &str = "/mnt/etc/fstab";
+/// Enclose the entries jade writes, so generating fstab again replaces them instead of
+/// adding duplicates
+const BEGIN_MARKER: &str = "# BEGIN jade";
+const END_MARKER: &str = "# END jade";
+
+/// A line of fstab
+#[derive(Debug)]
+struct Entry {
+    /// The block device the entry was generated for, written as a comment above it
+    device: String,
+    source: String,
+    mountpoint: String,
+    filesystem: String,
+    options: String,
+    pass: u8,
+}
+
+/// Reads `blkid --output export` of a block device
+fn probe(device: &str) -> String {
+    exec_output(
+        "blkid",
+        vec![
+            String::from("--output"),
+            String::from("export"),
+            String::from(device),
+        ],
+    )
+    .unwrap_or_default()
+}
+
+/// How the entry refers to `device`, falls back to the UUID and then the device path
+/// when it doesn't have what `reference` asks for, e.g. logical volumes have no PARTUUID
+fn source(device: &str, probe: &str, reference: FstabReference) -> String {
+    let key = match reference {
+        FstabReference::Uuid => "UUID",
+        FstabReference::Partuuid => "PARTUUID",
+        FstabReference::Label => "LABEL",
+    };
+    [key, "UUID"]
+        .iter()
+        .find_map(|key| probe_value(probe, key).map(|value| format!("{key}={value}")))
+        .unwrap_or_else(|| String::from(device))
+}
+
+/// The fsck pass, the root is checked first. btrfs and xfs check themselves when mounting.
+fn pass(mountpoint: &str, filesystem: &str) -> u8 {
+    match (mountpoint, filesystem) {
+        (_, "btrfs" | "xfs" | "swap" | "tmpfs") => 0,
+        ("/", _) => 1,
+        _ => 2,
+    }
+}
+
+/// The mount options of an entry. FAT has no permissions of its own, so the EFI system
+/// partition is only made readable for root.
+fn options(filesystem: &str, options: &[String]) -> String {
+    let mut options = options.to_vec();
+    if filesystem == "vfat"
+        && !options
+            .iter()
+            .any(|o| o.starts_with("umask=") || o.starts_with("fmask=") || o.starts_with("dmask="))
+    {
+        options.push(String::from("fmask=0077"));
+        options.push(String::from("dmask=0077"));
+    }
+    if options.is_empty() {
+        options.push(String::from("defaults"));
+    }
+    options.join(",")
+}
+
+fn render(entries: &[Entry]) -> String {
+    entries
+        .iter()
+        .map(|entry| {
+            format!(
+                "# {}\n{}\t{}\t{}\t{}\t0 {}\n\n",
+                entry.device,
+                entry.source,
+                entry.mountpoint,
+                entry.filesystem,
+                entry.options,
+                entry.pass
+            )
+        })
+        .collect()
+}
+
+/// Puts `block` between the markers at the end of `existing`, dropping what an earlier run
+/// put there
+fn replace_block(existing: &str, block: &str) -> String {
+    let mut kept = Vec::new();
+    let mut inside = false;
+    for line in existing.lines() {
+        match line {
+            BEGIN_MARKER => inside = true,
+            END_MARKER => inside = false,
+            _ if !inside => kept.push(line),
+            _ => {}
+        }
+    }
+    let kept = kept.join("\n");
+    let kept = kept.trim_end();
+    let separator = if kept.is_empty() { "" } else { "\n\n" };
+    format!("{kept}{separator}{BEGIN_MARKER}\n{block}{END_MARKER}\n")
+}
+
+/// The block devices mounted below /mnt in `proc_mounts`, with their mountpoint in the
+/// installed system. Spaces stay escaped as `\040`, which fstab reads the same way, and
+/// `subvolid=` is left out as it changes when a snapshot is restored.
+fn parse_mounts(proc_mounts: &str) -> Vec<Mount> {
+    proc_mounts
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let [device, mountpoint, _, options, ..] = fields[..] else {
+                return None;
+            };
+            if !device.starts_with("/dev/") {
+                return None;
+            }
+            let mountpoint = match mountpoint.strip_prefix("/mnt")? {
+                "" => String::from("/"),
+                rest if rest.starts_with('/') => String::from(rest),
+                _ => return None,
+            };
+            Some(Mount {
+                device: String::from(device),
+                mountpoint,
+                options: options
+                    .split(',')
+                    .filter(|o| !o.starts_with("subvolid=") && *o != "rw")
+                    .map(String::from)
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+/// What is mounted below /mnt, for when jade didn't mount the filesystems itself
+pub fn mounted() -> Vec<Mount> {
+    match std::fs::read_to_string("/proc/self/mounts") {
+        Ok(proc_mounts) => parse_mounts(&proc_mounts),
+        Err(e) => crash(format!("Read /proc/self/mounts  ERROR: {e}"), 1),
+    }
+}
+
+/// Writes the fstab of the system installed below /mnt with `mounts`, `swaps` (block devices
+/// or swapfiles) and a tmpfs on /tmp. Earlier entries of jade are replaced.
+pub fn write(mounts: &[Mount], swaps: &[String], reference: FstabReference) {
+    let mut mounts = mounts.to_vec();
+    mounts.sort_by_key(|mount| mount.mountpoint.len());
+    let mut entries: Vec<Entry> = mounts
+        .iter()
+        .map(|mount| {
+            let probe = probe(&mount.device);
+            let filesystem = probe_value(&probe, "TYPE").unwrap_or("auto");
+            Entry {
+                device: mount.device.clone(),
+                source: source(&mount.device, &probe, reference),
+                mountpoint: mount.mountpoint.clone(),
+                filesystem: String::from(filesystem),
+                options: options(filesystem, &mount.options),
+                pass: pass(&mount.mountpoint, filesystem),
+            }
+        })
+        .collect();
+    entries.push(Entry {
+        device: String::from("tmpfs"),
+        source: String::from("tmpfs"),
+        mountpoint: String::from("/tmp"),
+        filesystem: String::from("tmpfs"),
+        options: String::from("defaults,nosuid,nodev,mode=1777"),
+        pass: 0,
+    });
+    for swap in swaps {
+        // swapfiles are given by their path in the installed system
+        let source = if swap.starts_with("/dev/") {
+            source(swap, &probe(swap), reference)
+        } else {
+            swap.clone()
+        };
+        entries.push(Entry {
+            device: swap.clone(),
+            source,
+            mountpoint: String::from("none"),
+            filesystem: String::from("swap"),
+            options: String::from("defaults"),
+            pass: 0,
+        });
+    }
+    let existing = std::fs::read_to_string(FSTAB_PATH).unwrap_or_default();
+    files_eval(
+        write_file(FSTAB_PATH, &replace_block(&existing, &render(&entries))),
+        "Generate fstab",
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_entries() {
+        let probe = "DEVNAME=/dev/sda1\nUUID=1234-ABCD\nTYPE=vfat\nPARTUUID=5678";
+        assert_eq!(
+            source("/dev/sda1", probe, FstabReference::Partuuid),
+            "PARTUUID=5678"
+        );
+        assert_eq!(
+            source("/dev/sda1", probe, FstabReference::Label),
+            "UUID=1234-ABCD"
+        );
+        assert_eq!(source("/dev/dm-0", "", FstabReference::Uuid), "/dev/dm-0");
+        assert_eq!(options("vfat", &[]), "fmask=0077,dmask=0077");
+        assert_eq!(
+            options(
+                "btrfs",
+                &[String::from("subvol=@"), String::from("noatime")]
+            ),
+            "subvol=@,noatime"
+        );
+        assert_eq!(pass("/", "ext4"), 1);
+        assert_eq!(pass("/", "btrfs"), 0);
+        assert_eq!(pass("/efi", "vfat"), 2);
+    }
+
+    #[test]
+    fn parses_proc_mounts() {
+        let proc_mounts = "proc /proc proc rw,nosuid 0 0\n\
+            /dev/sda2 /mnt btrfs rw,relatime,subvolid=256,subvol=/@ 0 0\n\
+            /dev/sda1 /mnt/efi vfat rw,fmask=0077 0 0\n\
+            /dev/sdb1 /mnt/my\\040data ext4 rw 0 0\n\
+            /dev/sdc1 /mnt2 ext4 rw 0 0\n\
+            /dev/vda1 / ext4 rw 0 0\n";
+        let mounts: Vec<(String, String, String)> = parse_mounts(proc_mounts)
+            .into_iter()
+            .map(|m| (m.device, m.mountpoint, m.options.join(",")))
+            .collect();
+        let expected = [
+            ("/dev/sda2", "/", "relatime,subvol=/@"),
+            ("/dev/sda1", "/efi", "fmask=0077"),
+            ("/dev/sdb1", "/my\\040data", ""),
+        ];
+        let expected: Vec<(String, String, String)> = expected
+            .iter()
+            .map(|(d, m, o)| (d.to_string(), m.to_string(), o.to_string()))
+            .collect();
+        assert_eq!(mounts, expected);
+    }
+
+    #[test]
+    fn replaces_earlier_entries() {
+        let header = "# Static information about the filesystems.\n";
+        let first = replace_block(header, "UUID=1\t/\text4\tdefaults\t0 1\n");
+        assert_eq!(
+            first,
+            "# Static information about the filesystems.\n\n# BEGIN jade\nUUID=1\t/\text4\tdefaults\t0 1\n# END jade\n"
+        );
+        let second = replace_block(&first, "UUID=2\t/\text4\tdefaults\t0 1\n");
+        assert_eq!(second, first.replace("UUID=1", "UUID=2"));
+    }
+}
@@ -0,0 +1,143 @@
+use crate::functions::disks;
+use crate::functions::layout::Size;
+use crate::internal::exec::*;
+use crate::internal::state;
+use crate::internal::*;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Installing into an image file instead of a disk, e.g. to build VM images
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Image {
+    /// The size to create the image with if it doesn't exist yet
+    #[serde(default)]
+    pub size: Option<Size>,
+    /// Convert the finished image to qcow2 at this path
+    #[serde(default)]
+    pub qcow2: Option<PathBuf>,
+}
+
+/// Whether the install destination is an image file rather than a block device
+pub fn is_image(device: &Path) -> bool {
+    device.is_file()
+}
+
+/// Errors unless `file` is an absolute path to a regular file or to nothing yet, so a
+/// block device is never mistaken for an image
+pub fn check_file(file: &Path) -> Result<(), String> {
+    if !file.is_absolute() {
+        return Err(format!(
+            "The image file {} has to be an absolute path",
+            file.display()
+        ));
+    }
+    if file.exists() && !is_image(file) {
+        return Err(format!("{} is not an image file", file.display()));
+    }
+    Ok(())
+}
+
+/// The size of an image in MiB, `size` if it still has to be created
+pub fn size_mib(file: &Path, size: Option<Size>) -> Result<u64, String> {
+    match (std::fs::metadata(file), size) {
+        (Ok(metadata), _) => Ok(metadata.len() / 1024 / 1024),
+        (Err(_), Some(Size::Mib(mib))) => Ok(mib),
+        (Err(_), Some(size)) => Err(format!(
+            "the image size has to be absolute, like 20GiB, not {size}"
+        )),
+        (Err(_), None) => Err(format!(
+            "{} doesn't exist, give a size to create it with",
+            file.display()
+        )),
+    }
+}
+
+/// Creates the image `file` if it doesn't exist yet and attaches it to a loop device with
+/// partition scanning, returns the loop device. It's remembered for `detach`.
+pub fn attach(file: &Path, size: Option<Size>) -> PathBuf {
+    let mib = match size_mib(file, size) {
+        Ok(mib) => mib,
+        Err(e) => crash(format!("Invalid image: {e}"), 1),
+    };
+    if !file.exists() {
+        log::info!("Creating the {mib}MiB image {}", file.display());
+        let created = std::fs::File::create(file).and_then(|f| f.set_len(mib * 1024 * 1024));
+        if let Err(e) = created {
+            crash(format!("Create image {file:?}  ERROR: {e}"), 1);
+        }
+    }
+    let loop_device = exec_output(
+        "losetup",
+        vec![
+            String::from("--find"),
+            String::from("--show"),
+            String::from("--partscan"),
+            file.to_string_lossy().to_string(),
+        ],
+    );
+    match loop_device {
+        Ok(loop_device) if loop_device.starts_with("/dev/") => {
+            log::info!("Attached {} to {loop_device}", file.display());
+            let image = file.to_string_lossy().to_string();
+            let attached = loop_device.clone();
+            state::update(|state| {
+                state.image = Some(image);
+                state.loop_device = Some(attached);
+            });
+            PathBuf::from(loop_device)
+        }
+        Ok(_) => crash(format!("Attach {file:?} to a loop device"), 1),
+        Err(e) => crash(format!("Attach {file:?} to a loop device  ERROR: {e}"), 1),
+    }
+}
+
+/// Unmounts the install, deactivates whatever runs on top of the loop device and detaches it,
+/// then optionally converts the image to qcow2
+pub fn detach(qcow2: Option<&Path>) {
+    let state = state::load();
+    let (Some(file), Some(loop_device)) = (state.image, state.loop_device) else {
+        crash("No image is attached", 1);
+    };
+    let mounts = std::fs::read_to_string("/proc/mounts").unwrap_or_default();
+    if mounts
+        .lines()
+        .any(|line| line.split_whitespace().nth(1) == Some("/mnt"))
+    {
+        exec_eval(
+            exec(
+                "umount",
+                vec![String::from("--recursive"), String::from("/mnt")],
+            ),
+            "unmount /mnt",
+        );
+    }
+    disks::release(Path::new(&loop_device));
+    exec_eval(
+        exec(
+            "losetup",
+            vec![String::from("--detach"), loop_device.clone()],
+        ),
+        format!("Detach {loop_device}").as_str(),
+    );
+    if let Some(qcow2) = qcow2 {
+        exec_eval(
+            exec(
+                "qemu-img",
+                vec![
+                    String::from("convert"),
+                    String::from("-f"),
+                    String::from("raw"),
+                    String::from("-O"),
+                    String::from("qcow2"),
+                    file.clone(),
+                    qcow2.to_string_lossy().to_string(),
+                ],
+            ),
+            format!("Convert {file} to qcow2").as_str(),
+        );
+    }
+    state::update(|state| {
+        state.image = None;
+        state.loop_device = None;
+    });
+}
@@ -0,0 +1,194 @@
+use crate::functions::{encryption, raid};
+use crate::internal::exec::*;
+use crate::internal::state::{self, InstallState};
+use crate::internal::*;
+
+const MKINITCPIO_CONF: &str = "/mnt/etc/mkinitcpio.conf";
+
+/// Storage hooks in the order they have to run in, all of them go before `filesystems`
+const STORAGE_HOOKS: [&str; 4] = ["mdadm_udev", "encrypt", "lvm2", "resume"];
+
+/// Returns the hooks of a `HOOKS=(...)` line from mkinitcpio.conf
+fn parse_hooks(line: &str) -> Vec<String> {
+    line.trim_start_matches("HOOKS=")
+        .trim_matches(|c| c == '(' || c == ')' || c == '"')
+        .split_whitespace()
+        .map(String::from)
+        .collect()
+}
+
+/// Returns the active `HOOKS=` line of mkinitcpio.conf
+fn hooks_line(conf: &str) -> Option<&str> {
+    conf.lines()
+        .map(str::trim)
+        .find(|line| line.starts_with("HOOKS="))
+}
+
+/// Switches a udev based initramfs over to the systemd hook and its sd- variants
+fn to_systemd(current: &[String]) -> Vec<String> {
+    current
+        .iter()
+        .filter_map(|hook| match hook.as_str() {
+            "udev" => Some(String::from("systemd")),
+            "keymap" => Some(String::from("sd-vconsole")),
+            "encrypt" => Some(String::from("sd-encrypt")),
+            // covered by sd-vconsole and systemd
+            "consolefont" | "resume" => None,
+            _ => Some(hook.clone()),
+        })
+        .collect()
+}
+
+/// Adds the storage hooks in `extra` to the `current` hooks, `systemd` switches the initramfs
+/// over to the systemd hook first. A systemd based initramfs gets the sd- variants and doesn't
+/// need the resume hook.
+pub fn add_storage_hooks(current: &[String], extra: &[String], systemd: bool) -> Vec<String> {
+    let mut hooks = if systemd {
+        to_systemd(current)
+    } else {
+        current.to_vec()
+    };
+    let systemd = hooks.iter().any(|h| h == "systemd");
+    for hook in STORAGE_HOOKS {
+        if !extra.iter().any(|h| h == hook) {
+            continue;
+        }
+        let hook = match (hook, systemd) {
+            ("encrypt", true) => "sd-encrypt",
+            // systemd resumes from hibernation on its own
+            ("resume", true) => continue,
+            (hook, _) => hook,
+        };
+        if hooks.iter().any(|h| h == hook) {
+            continue;
+        }
+        let position = hooks
+            .iter()
+            .position(|h| h == "filesystems")
+            .unwrap_or(hooks.len());
+        hooks.insert(position, String::from(hook));
+    }
+    hooks
+}
+
+/// Whether the initramfs of the installed system is built with the systemd hook
+pub fn uses_systemd() -> bool {
+    let conf = std::fs::read_to_string(MKINITCPIO_CONF).unwrap_or_default();
+    hooks_line(&conf).is_some_and(|line| parse_hooks(line).iter().any(|h| h == "systemd"))
+}
+
+/// Installs what the storage setup needs in the initramfs and rebuilds it
+pub fn configure() {
+    let state = state::load();
+    if state.initramfs_hooks.is_empty() && state.packages.is_empty() && !state.systemd_initramfs {
+        return;
+    }
+    if !state.packages.is_empty() {
+        install(state.packages.iter().map(String::as_str).collect());
+    }
+    let conf = match std::fs::read_to_string(MKINITCPIO_CONF) {
+        Ok(conf) => conf,
+        Err(e) => crash(format!("Read {MKINITCPIO_CONF}  ERROR: {e}"), 1),
+    };
+    let line = match hooks_line(&conf) {
+        Some(line) => line,
+        None => crash(format!("No HOOKS= line in {MKINITCPIO_CONF}"), 1),
+    };
+    if state.initramfs_hooks.iter().any(|h| h == "mdadm_udev") {
+        raid::write_mdadm_conf();
+    }
+    let hooks = add_storage_hooks(
+        &parse_hooks(line),
+        &state.initramfs_hooks,
+        state.systemd_initramfs,
+    );
+    files_eval(
+        files::sed_file(
+            MKINITCPIO_CONF,
+            line,
+            &format!("HOOKS=({})", hooks.join(" ")),
+        ),
+        "Add storage hooks to mkinitcpio.conf",
+    );
+    exec_eval(
+        exec_chroot("mkinitcpio", vec![String::from("-P")]),
+        "Rebuild initramfs",
+    );
+}
+
+/// Kernel parameters the initramfs needs to find the root
+pub fn kernel_params(state: &InstallState) -> Vec<String> {
+    let mut params = Vec::new();
+    if let Some(root) = &state.luks_root {
+        params.extend(encryption::root_kernel_params(
+            root,
+            state.systemd_initramfs || uses_systemd(),
+        ));
+        // the root is either the unlocked volume itself or a logical volume on it
+        let device = match &state.root_device {
+            Some(device) => device.clone(),
+            None => format!("/dev/mapper/{}", root.name),
+        };
+        params.push(format!("root={device}"));
+    }
+    if let Some(resume) = &state.resume {
+        params.push(format!("resume={resume}"));
+    }
+    if let Some(offset) = state.resume_offset {
+        params.push(format!("resume_offset={offset}"));
+    }
+    params
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hooks(hooks: &str) -> Vec<String> {
+        hooks.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn parses_hooks_line() {
+        assert_eq!(
+            parse_hooks("HOOKS=(base udev autodetect block filesystems fsck)"),
+            hooks("base udev autodetect block filesystems fsck")
+        );
+    }
+
+    #[test]
+    fn adds_hooks_before_filesystems() {
+        assert_eq!(
+            add_storage_hooks(
+                &hooks("base udev autodetect keyboard keymap block filesystems fsck"),
+                &hooks("resume encrypt"),
+                false
+            ),
+            hooks("base udev autodetect keyboard keymap block encrypt resume filesystems fsck")
+        );
+    }
+
+    #[test]
+    fn uses_systemd_hooks() {
+        assert_eq!(
+            add_storage_hooks(
+                &hooks("base systemd autodetect keyboard sd-vconsole block filesystems fsck"),
+                &hooks("encrypt resume"),
+                false
+            ),
+            hooks("base systemd autodetect keyboard sd-vconsole block sd-encrypt filesystems fsck")
+        );
+    }
+
+    #[test]
+    fn switches_to_systemd_for_token_unlocking() {
+        assert_eq!(
+            add_storage_hooks(
+                &hooks("base udev autodetect keyboard keymap consolefont block filesystems fsck"),
+                &hooks("encrypt"),
+                true
+            ),
+            hooks("base systemd autodetect keyboard sd-vconsole block sd-encrypt filesystems fsck")
+        );
+    }
+}
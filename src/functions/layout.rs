@@ -0,0 +1,1218 @@
+use crate::args::{RaidBackend, RaidLevel, WipeMethod};
+use crate::functions::partition;
+use crate::internal::secret::Secret;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Where the first partition starts, keeps everything 1MiB aligned
+const FIRST_PARTITION_START_MIB: u64 = 1;
+/// Space left free at the end of the disk for the backup GPT header
+const END_RESERVED_MIB: u64 = 1;
+/// Space of a physical volume taken up by LVM metadata and rounding to extents
+const LVM_OVERHEAD_MIB: u64 = 8;
+/// Space taken up by the LUKS2 header
+const LUKS_HEADER_MIB: u64 = 16;
+/// The filesystem of the partition holding the LVM physical volume
+pub const LVM_FILESYSTEM: &str = "lvm";
+/// The GPT name of the partition the default layout makes for unakite
+pub const UNAKITE_LABEL: &str = "unakite";
+
+/// GPT partition type GUIDs from the Discoverable Partitions Specification, they let the
+/// firmware and systemd-gpt-auto-generator find the partitions without an fstab
+pub mod gpt_type {
+    pub const ESP: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
+    pub const XBOOTLDR: &str = "BC13C2FF-59E6-4262-A352-B275FD6F7172";
+    #[cfg(target_arch = "x86_64")]
+    pub const ROOT: &str = "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709";
+    #[cfg(target_arch = "aarch64")]
+    pub const ROOT: &str = "B921B045-1DF0-41C3-AF44-4C6F280D3FAE";
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    pub const ROOT: &str = LINUX;
+    pub const HOME: &str = "933AC7E1-2EB4-4F13-B844-0E14E2AEF915";
+    pub const SRV: &str = "3B8F8425-20E0-4F3B-907F-1A25A76F98E8";
+    pub const VAR: &str = "4D21B016-B534-45C2-A9FB-5C16E091FD2D";
+    pub const SWAP: &str = "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F";
+    pub const LVM: &str = "E6D6D379-F507-44C2-A23C-238F2A3DF928";
+    pub const RAID: &str = "A19D880F-05FC-4D3B-A006-743F0F84911E";
+    pub const LINUX: &str = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+}
+
+/// The size of a partition in an automatic layout
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Size {
+    /// An absolute size in MiB, written as e.g. `512MiB`, `20GiB` or `1TiB`
+    Mib(u64),
+    /// A percentage of the whole disk, written as e.g. `25%`
+    Percent(u64),
+    /// Whatever is left after all other partitions, written as `rest`
+    Remainder,
+}
+
+impl FromStr for Size {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("rest") || s.eq_ignore_ascii_case("remainder") {
+            return Ok(Size::Remainder);
+        }
+        if let Some(percent) = s.strip_suffix('%') {
+            return match percent.trim().parse::<u64>() {
+                Ok(percent) if percent > 0 && percent <= 100 => Ok(Size::Percent(percent)),
+                _ => Err(format!("Invalid percentage {s:?}, expected 1% to 100%")),
+            };
+        }
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let number = number
+            .parse::<u64>()
+            .map_err(|_| format!("Invalid size {s:?}, expected e.g. 512MiB, 20GiB, 25% or rest"))?;
+        let factor = match unit.trim().to_lowercase().as_str() {
+            "m" | "mib" => 1,
+            "g" | "gib" => 1024,
+            "t" | "tib" => 1024 * 1024,
+            _ => {
+                return Err(format!(
+                    "Invalid size unit in {s:?}, expected MiB, GiB or TiB"
+                ))
+            }
+        };
+        if number == 0 {
+            return Err(format!("Invalid size {s:?}, partitions can't be empty"));
+        }
+        // sizes are converted to bytes later on, so those have to fit as well
+        match number
+            .checked_mul(factor)
+            .filter(|mib| mib.checked_mul(1024 * 1024).is_some())
+        {
+            Some(mib) => Ok(Size::Mib(mib)),
+            None => Err(format!("Invalid size {s:?}, it's too big")),
+        }
+    }
+}
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Size::Mib(mib) => write!(f, "{mib}MiB"),
+            Size::Percent(percent) => write!(f, "{percent}%"),
+            Size::Remainder => f.write_str("rest"),
+        }
+    }
+}
+
+impl Serialize for Size {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Size {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// An EFI system partition that already exists, e.g. the one of Windows on another disk
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ExistingEsp {
+    /// Look for the only EFI system partition on the other disks, written as `auto`
+    Detect,
+    Device(PathBuf),
+}
+
+impl FromStr for ExistingEsp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "" => Err(String::from("Expected an EFI system partition or auto")),
+            "auto" => Ok(ExistingEsp::Detect),
+            device => Ok(ExistingEsp::Device(PathBuf::from(device))),
+        }
+    }
+}
+
+impl fmt::Display for ExistingEsp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExistingEsp::Detect => f.write_str("auto"),
+            ExistingEsp::Device(device) => write!(f, "{}", device.display()),
+        }
+    }
+}
+
+impl Serialize for ExistingEsp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ExistingEsp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// A single partition of an automatic layout
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LayoutPartition {
+    /// How big the partition should be
+    pub size: Size,
+    /// GPT partition type GUID, or MBR type id like `0x83` on non-EFI installs. GPT partitions
+    /// default to the Discoverable Partitions type for their mountpoint.
+    #[serde(default)]
+    pub r#type: Option<String>,
+    /// GPT partition name, defaults to one like `esp`, `root` or `home`. At most 36
+    /// characters without whitespace or quotes, as it is passed on to parted unquoted.
+    #[serde(default)]
+    pub label: Option<String>,
+    /// The filesystem to format the partition with
+    pub filesystem: String,
+    /// Where to mount the partition in the installed system, e.g. `/` or `/efi`
+    #[serde(default)]
+    pub mountpoint: Option<String>,
+    /// Extra arguments passed to mkfs
+    #[serde(default)]
+    pub mkfs_options: Vec<String>,
+}
+
+impl LayoutPartition {
+    pub fn new(size: Size, filesystem: &str, mountpoint: Option<&str>) -> Self {
+        Self {
+            size,
+            r#type: None,
+            label: None,
+            filesystem: filesystem.to_string(),
+            mountpoint: mountpoint.map(String::from),
+            mkfs_options: Vec::new(),
+        }
+    }
+
+    /// Whether this is the EFI system partition
+    pub fn is_esp(&self) -> bool {
+        self.filesystem == "vfat"
+            && self
+                .mountpoint
+                .as_deref()
+                .is_some_and(|m| ["/efi", "/boot/efi", "/boot"].contains(&m))
+    }
+}
+
+/// LUKS2 encryption of an automatic layout
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Encryption {
+    /// The passphrase to unlock the encrypted partitions with
+    pub passphrase: Secret,
+    /// Whether to encrypt a separate /home partition as well as the root
+    #[serde(default)]
+    pub home: bool,
+    /// Unlock with a TPM2 chip as well as the passphrase
+    #[serde(default)]
+    pub tpm2: Option<Tpm2>,
+    /// Unlock with a FIDO2 token as well as the passphrase
+    #[serde(default)]
+    pub fido2: bool,
+}
+
+/// TPM2 unlocking of encrypted partitions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tpm2 {
+    /// The TPM2 device to enroll, `auto` uses the only one in the system
+    #[serde(default = "default_tpm2_device")]
+    pub device: String,
+    /// The PCRs the key is bound to, by default just the Secure Boot state
+    #[serde(default = "default_tpm2_pcrs")]
+    pub pcrs: Vec<u32>,
+}
+
+fn default_tpm2_device() -> String {
+    String::from("auto")
+}
+
+fn default_tpm2_pcrs() -> Vec<u32> {
+    vec![7]
+}
+
+impl Default for Tpm2 {
+    fn default() -> Self {
+        Self {
+            device: default_tpm2_device(),
+            pcrs: default_tpm2_pcrs(),
+        }
+    }
+}
+
+/// A logical volume in the volume group of an automatic layout
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogicalVolume {
+    /// Name of the logical volume, e.g. `root`, it ends up in /dev/<volume group>/<name>
+    pub name: String,
+    /// How big the volume should be, percentages are of the whole volume group
+    pub size: Size,
+    /// The filesystem to format the volume with
+    pub filesystem: String,
+    /// Where to mount the volume in the installed system
+    #[serde(default)]
+    pub mountpoint: Option<String>,
+    /// Extra arguments passed to mkfs
+    #[serde(default)]
+    pub mkfs_options: Vec<String>,
+}
+
+/// An LVM volume group on the `lvm` partition of an automatic layout
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Lvm {
+    /// Name of the volume group
+    #[serde(default = "default_volume_group")]
+    pub volume_group: String,
+    /// The logical volumes, created in this order
+    pub volumes: Vec<LogicalVolume>,
+}
+
+fn default_volume_group() -> String {
+    String::from("jade")
+}
+
+impl Lvm {
+    /// Checks the names and sizes of the logical volumes
+    pub fn validate(&self) -> Result<(), String> {
+        let mut names: Vec<&str> = Vec::new();
+        for volume in &self.volumes {
+            if volume.name.is_empty() || volume.name.contains('/') {
+                return Err(format!("Invalid logical volume name {:?}", volume.name));
+            }
+            if names.contains(&volume.name.as_str()) {
+                return Err(format!(
+                    "Logical volume {} is defined more than once",
+                    volume.name
+                ));
+            }
+            names.push(&volume.name);
+        }
+        if self
+            .volumes
+            .iter()
+            .filter(|v| v.size == Size::Remainder)
+            .count()
+            > 1
+        {
+            return Err(String::from(
+                "Only one logical volume can take up the rest of the volume group",
+            ));
+        }
+        Ok(())
+    }
+
+    /// A volume group with a single root volume taking up all of it
+    pub fn root_only(filesystem: &str) -> Self {
+        Self {
+            volume_group: default_volume_group(),
+            volumes: vec![LogicalVolume {
+                name: String::from("root"),
+                size: Size::Remainder,
+                filesystem: filesystem.to_string(),
+                mountpoint: Some(String::from("/")),
+                mkfs_options: Vec::new(),
+            }],
+        }
+    }
+}
+
+/// A subvolume of a btrfs root
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subvolume {
+    /// Name of the subvolume, e.g. `@snapshots`
+    pub name: String,
+    /// Where to mount the subvolume in the installed system
+    pub mountpoint: String,
+    /// Mount options for just this subvolume, e.g. `nodatacow`
+    #[serde(default)]
+    pub mount_options: Vec<String>,
+}
+
+impl Subvolume {
+    pub fn new(name: &str, mountpoint: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            mountpoint: mountpoint.to_string(),
+            mount_options: Vec::new(),
+        }
+    }
+}
+
+/// How a btrfs root gets split into subvolumes and mounted
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Btrfs {
+    /// The subvolumes to create, defaults to `@` at / and `@home` at /home
+    #[serde(default)]
+    pub subvolumes: Vec<Subvolume>,
+    /// Mount options for every subvolume, e.g. `compress=zstd` or `noatime`
+    #[serde(default)]
+    pub mount_options: Vec<String>,
+}
+
+/// Swap space of an automatic layout
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Swap {
+    /// How big the swap should be, big enough to hold the RAM when hibernating
+    pub size: Size,
+    /// Use a swapfile on the root instead of a partition (or logical volume when using LVM)
+    #[serde(default)]
+    pub file: bool,
+    /// Resume from the swap after hibernating
+    #[serde(default)]
+    pub hibernate: bool,
+}
+
+impl Swap {
+    /// Where the swapfile goes, btrfs keeps it on its own subvolume so snapshots don't include it
+    pub fn file_path(btrfs: bool) -> &'static str {
+        if btrfs {
+            "/swap/swapfile"
+        } else {
+            "/swapfile"
+        }
+    }
+}
+
+/// Shrinking a partition of another OS to make room for a dual-boot install
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Shrink {
+    /// Number of the partition to shrink
+    pub partition: usize,
+    /// How much space to take from it, e.g. `50GiB`
+    pub by: Size,
+}
+
+/// A software RAID across several disks that all get the same partitions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Raid {
+    pub level: RaidLevel,
+    /// mdadm arrays or btrfs' own multi-device profiles
+    #[serde(default)]
+    pub backend: RaidBackend,
+    /// The other disks of the RAID, the install device is always the first one
+    pub devices: Vec<PathBuf>,
+}
+
+/// Another disk of an automatic layout with partitions of its own, e.g. /home on a hard drive
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiskLayout {
+    pub device: PathBuf,
+    pub partitions: Vec<LayoutPartition>,
+}
+
+/// Errors if parted can't set `label` as a GPT partition name, which holds 36 UTF-16 units
+fn check_label(label: &str) -> Result<(), String> {
+    if label.is_empty() {
+        return Err(String::from("Partition labels can't be empty"));
+    }
+    if label
+        .chars()
+        .any(|c| c.is_whitespace() || c == '"' || c == '\'' || c.is_control())
+    {
+        return Err(format!(
+            "The partition label {label:?} can't contain whitespace or quotes"
+        ));
+    }
+    if label.encode_utf16().count() > 36 {
+        return Err(format!(
+            "The partition label {label:?} is longer than the 36 characters GPT allows"
+        ));
+    }
+    Ok(())
+}
+
+/// How automatic partitioning lays out the install destination
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Layout {
+    pub partitions: Vec<LayoutPartition>,
+    pub encryption: Option<Encryption>,
+    pub lvm: Option<Lvm>,
+    pub raid: Option<Raid>,
+    pub btrfs: Btrfs,
+    pub swap: Option<Swap>,
+    /// Partition to shrink when installing alongside another OS
+    pub shrink: Option<Shrink>,
+    /// How to clean the disks up before partitioning them
+    pub wipe: Option<WipeMethod>,
+    /// Other disks that get partitioned along with the install device
+    pub disks: Vec<DiskLayout>,
+    /// Mount this EFI system partition instead of creating one
+    pub esp: Option<ExistingEsp>,
+}
+
+impl Layout {
+    /// The layout jade has always used: a 512MiB boot or EFI partition,
+    /// optionally a ~10GiB Unakite root and a btrfs root taking up the rest of the disk
+    pub fn default_for(efi: bool, unakite: bool) -> Self {
+        let mut partitions = vec![if efi {
+            LayoutPartition::new(Size::Mib(512), "vfat", Some("/efi"))
+        } else {
+            LayoutPartition::new(Size::Mib(512), "ext4", Some("/boot"))
+        }];
+        if unakite {
+            // unakite is always btrfs, whatever the root gets formatted as
+            partitions.push(LayoutPartition {
+                label: Some(String::from(UNAKITE_LABEL)),
+                ..LayoutPartition::new(Size::Mib(9536), "btrfs", None)
+            });
+        }
+        partitions.push(LayoutPartition::new(Size::Remainder, "btrfs", Some("/")));
+        Self {
+            partitions,
+            encryption: None,
+            lvm: None,
+            raid: None,
+            btrfs: Btrfs::default(),
+            swap: None,
+            shrink: None,
+            wipe: None,
+            disks: Vec::new(),
+            esp: None,
+        }
+    }
+
+    /// Takes the EFI system partition out of the layout when an existing one is used instead,
+    /// returns where it gets mounted
+    pub fn take_esp(&mut self) -> Option<String> {
+        let position = self.partitions.iter().position(|p| p.is_esp())?;
+        self.partitions.remove(position).mountpoint
+    }
+
+    /// The layout of one of the other disks, to partition it on its own
+    pub fn for_disk(&self, disk: &DiskLayout) -> Layout {
+        Layout {
+            partitions: disk.partitions.clone(),
+            wipe: self.wipe,
+            ..Layout::default()
+        }
+    }
+
+    /// Adds swap to the layout, a swap partition goes right before the partition taking up
+    /// the rest of the disk. With LVM it becomes a logical volume instead.
+    pub fn set_swap(&mut self, swap: Swap) {
+        if !swap.file {
+            if let Some(lvm) = self.lvm.as_mut() {
+                lvm.volumes.push(LogicalVolume {
+                    name: String::from("swap"),
+                    size: swap.size,
+                    filesystem: String::from("swap"),
+                    mountpoint: None,
+                    mkfs_options: Vec::new(),
+                });
+            } else {
+                let position = self
+                    .partitions
+                    .iter()
+                    .position(|p| p.size == Size::Remainder)
+                    .unwrap_or(self.partitions.len());
+                self.partitions
+                    .insert(position, LayoutPartition::new(swap.size, "swap", None));
+            }
+        }
+        self.swap = Some(swap);
+    }
+
+    /// Formats the root with `filesystem` instead of btrfs. GRUB can't read bcachefs,
+    /// so that also gets a separate /boot unless the layout already has one.
+    pub fn set_root_filesystem(&mut self, filesystem: &str) {
+        let volumes = self.lvm.iter_mut().flat_map(|lvm| lvm.volumes.iter_mut());
+        for volume in volumes.filter(|v| v.mountpoint.as_deref() == Some("/")) {
+            volume.filesystem = filesystem.to_string();
+        }
+        for partition in self
+            .partitions
+            .iter_mut()
+            .filter(|p| p.mountpoint.as_deref() == Some("/"))
+        {
+            partition.filesystem = filesystem.to_string();
+        }
+        if filesystem == "bcachefs" && !self.has_mountpoint("/boot") {
+            let position = self
+                .partitions
+                .iter()
+                .position(|p| p.size == Size::Remainder || self.holds_root(p))
+                .unwrap_or(self.partitions.len());
+            self.partitions.insert(
+                position,
+                LayoutPartition::new(Size::Mib(1024), "ext4", Some("/boot")),
+            );
+        }
+    }
+
+    /// Moves the root partition into an LVM volume group, `volumes` replaces the default
+    /// single root volume
+    pub fn use_lvm(&mut self, lvm: Option<Lvm>) {
+        let Some(root) = self
+            .partitions
+            .iter_mut()
+            .find(|p| p.mountpoint.as_deref() == Some("/"))
+        else {
+            return;
+        };
+        self.lvm = Some(lvm.unwrap_or_else(|| Lvm::root_only(&root.filesystem)));
+        root.filesystem = String::from(LVM_FILESYSTEM);
+        root.mountpoint = None;
+    }
+
+    /// Mountpoints of all partitions, logical volumes and configured btrfs subvolumes
+    fn mountpoints(&self) -> impl Iterator<Item = &str> {
+        let volumes = self.lvm.iter().flat_map(|lvm| lvm.volumes.iter());
+        // the root subvolume is mounted in place of the root partition
+        let subvolumes = self
+            .btrfs
+            .subvolumes
+            .iter()
+            .map(|s| s.mountpoint.as_str())
+            .filter(|mountpoint| *mountpoint != "/");
+        let disks = self.disks.iter().flat_map(|disk| disk.partitions.iter());
+        self.partitions
+            .iter()
+            .chain(disks)
+            .filter_map(|p| p.mountpoint.as_deref())
+            .chain(volumes.filter_map(|v| v.mountpoint.as_deref()))
+            .chain(subvolumes)
+    }
+
+    /// The filesystem the root is formatted with, whether it's on a partition or logical volume
+    pub fn root_filesystem(&self) -> Option<&str> {
+        let volumes = self.lvm.iter().flat_map(|lvm| lvm.volumes.iter());
+        self.partitions
+            .iter()
+            .map(|p| (p.mountpoint.as_deref(), p.filesystem.as_str()))
+            .chain(volumes.map(|v| (v.mountpoint.as_deref(), v.filesystem.as_str())))
+            .find(|(mountpoint, _)| *mountpoint == Some("/"))
+            .map(|(_, filesystem)| filesystem)
+    }
+
+    /// The subvolumes of a btrfs root, `@` and `@home` unless configured otherwise.
+    /// `@home` is left out when /home is a separate partition.
+    pub fn subvolumes(&self) -> Vec<Subvolume> {
+        if !self.btrfs.subvolumes.is_empty() {
+            let mut subvolumes = self.btrfs.subvolumes.clone();
+            let swapfile = self.swap.as_ref().is_some_and(|swap| swap.file);
+            if swapfile && !subvolumes.iter().any(|s| s.mountpoint == "/swap") {
+                subvolumes.push(Subvolume::new("@swap", "/swap"));
+            }
+            return subvolumes;
+        }
+        let mut subvolumes = vec![Subvolume::new("@", "/")];
+        if !self.has_mountpoint("/home") {
+            subvolumes.push(Subvolume::new("@home", "/home"));
+        }
+        if self.swap.as_ref().is_some_and(|swap| swap.file) {
+            subvolumes.push(Subvolume::new("@swap", "/swap"));
+        }
+        subvolumes
+    }
+
+    pub fn has_mountpoint(&self, mountpoint: &str) -> bool {
+        self.mountpoints().any(|m| m == mountpoint)
+    }
+
+    /// Whether the root ends up on `partition`, directly or on a logical volume
+    pub fn holds_root(&self, partition: &LayoutPartition) -> bool {
+        match partition.mountpoint.as_deref() {
+            Some(mountpoint) => mountpoint == "/",
+            None => {
+                partition.filesystem == LVM_FILESYSTEM
+                    && self.lvm.as_ref().is_some_and(|lvm| {
+                        lvm.volumes
+                            .iter()
+                            .any(|v| v.mountpoint.as_deref() == Some("/"))
+                    })
+            }
+        }
+    }
+
+    /// Whether a partition of a RAID layout is just copied to every disk instead of being part
+    /// of the array. EFI system partitions can't be in an array and btrfs RAID only covers btrfs.
+    pub fn is_raid_copy(&self, partition: &LayoutPartition) -> bool {
+        match &self.raid {
+            Some(raid) => {
+                partition.filesystem == "vfat"
+                    || (raid.backend == RaidBackend::Btrfs && partition.filesystem != "btrfs")
+            }
+            None => false,
+        }
+    }
+
+    /// The GPT type GUID of a partition, the one given in the layout or else the one the
+    /// Discoverable Partitions Specification has for it
+    pub fn gpt_type(&self, partition: &LayoutPartition) -> String {
+        if let Some(r#type) = &partition.r#type {
+            return r#type.clone();
+        }
+        let mdadm = self
+            .raid
+            .as_ref()
+            .is_some_and(|raid| raid.backend == RaidBackend::Mdadm);
+        let r#type = if mdadm && !self.is_raid_copy(partition) {
+            gpt_type::RAID
+        } else if partition.is_esp() {
+            gpt_type::ESP
+        } else if partition.filesystem == LVM_FILESYSTEM {
+            gpt_type::LVM
+        } else if partition.filesystem == "swap" {
+            gpt_type::SWAP
+        } else {
+            match partition.mountpoint.as_deref() {
+                Some("/") => gpt_type::ROOT,
+                Some("/home") => gpt_type::HOME,
+                Some("/boot") => gpt_type::XBOOTLDR,
+                Some("/srv") => gpt_type::SRV,
+                Some("/var") => gpt_type::VAR,
+                _ => gpt_type::LINUX,
+            }
+        };
+        String::from(r#type)
+    }
+
+    /// The GPT partition name, the label given in the layout or else one after what the
+    /// partition is used for
+    pub fn gpt_label(&self, partition: &LayoutPartition) -> String {
+        if let Some(label) = &partition.label {
+            return label.clone();
+        }
+        if partition.is_esp() {
+            return String::from("esp");
+        }
+        match (
+            partition.filesystem.as_str(),
+            partition.mountpoint.as_deref(),
+        ) {
+            ("swap", _) => String::from("swap"),
+            (LVM_FILESYSTEM, _) => String::from("lvm"),
+            (_, Some("/")) => String::from("root"),
+            (_, Some(mountpoint)) => mountpoint.trim_start_matches('/').replace('/', "-"),
+            (_, None) => String::from("linux"),
+        }
+    }
+
+    /// The /dev/mapper name of a partition if it gets encrypted
+    pub fn luks_name(&self, partition: &LayoutPartition) -> Option<&'static str> {
+        let encryption = self.encryption.as_ref()?;
+        if partition.filesystem == LVM_FILESYSTEM {
+            // the whole volume group is encrypted, root and /home included
+            return Some("cryptlvm");
+        }
+        match partition.mountpoint.as_deref() {
+            Some("/") => Some("cryptroot"),
+            Some("/home") if encryption.home => Some("crypthome"),
+            _ => None,
+        }
+    }
+
+    /// Whether GRUB has to unlock the root itself because /boot isn't a separate partition
+    pub fn needs_grub_cryptodisk(&self) -> bool {
+        self.encryption.is_some() && !self.has_mountpoint("/boot")
+    }
+
+    /// Works out where each partition starts and ends on a disk of `disk_mib` MiB.
+    /// Returns `(start, end)` pairs in MiB, in the same order as the partitions.
+    pub fn plan(&self, disk_mib: u64) -> Result<Vec<(u64, u64)>, String> {
+        let end = disk_mib.saturating_sub(END_RESERVED_MIB);
+        self.plan_in(FIRST_PARTITION_START_MIB, end, disk_mib)
+    }
+
+    /// Like `plan`, but fits the partitions into the free space from `start` to `end` MiB,
+    /// percentages are of the free space
+    pub fn plan_free_space(&self, start: u64, end: u64) -> Result<Vec<(u64, u64)>, String> {
+        self.plan_in(start, end, end.saturating_sub(start))
+    }
+
+    /// Fits the partitions between `start` and `end` MiB, percentages are of `percent_of` MiB
+    fn plan_in(&self, start: u64, end: u64, percent_of: u64) -> Result<Vec<(u64, u64)>, String> {
+        if self.partitions.is_empty() {
+            return Err(String::from("The partition layout is empty"));
+        }
+        let remainders = self
+            .partitions
+            .iter()
+            .filter(|p| p.size == Size::Remainder)
+            .count();
+        if remainders > 1 {
+            return Err(String::from(
+                "Only one partition in the layout can take up the rest of the disk",
+            ));
+        }
+        let usable = end.saturating_sub(start);
+        let fixed = |size: Size| match size {
+            Size::Mib(mib) => mib,
+            Size::Percent(percent) => percent_of * percent / 100,
+            Size::Remainder => 0,
+        };
+        let used = self
+            .partitions
+            .iter()
+            .map(|p| fixed(p.size))
+            .fold(0, u64::saturating_add);
+        if used > usable || (remainders == 1 && used == usable) {
+            return Err(format!(
+                "The partition layout needs {}MiB but the disk only has {usable}MiB of usable space",
+                used.saturating_add(remainders as u64)
+            ));
+        }
+        let mut start = start;
+        let mut plan = Vec::new();
+        for partition in &self.partitions {
+            let size = match partition.size {
+                Size::Remainder => usable - used,
+                size => fixed(size),
+            };
+            if partition.filesystem == LVM_FILESYSTEM {
+                self.check_volumes_fit(size)?;
+            }
+            plan.push((start, start + size));
+            start += size;
+        }
+        Ok(plan)
+    }
+
+    /// Checks that the logical volumes fit into an `lvm` partition of `partition_mib` MiB
+    fn check_volumes_fit(&self, partition_mib: u64) -> Result<(), String> {
+        let Some(lvm) = &self.lvm else {
+            return Ok(());
+        };
+        let mut available = partition_mib.saturating_sub(LVM_OVERHEAD_MIB);
+        if self.encryption.is_some() {
+            available = available.saturating_sub(LUKS_HEADER_MIB);
+        }
+        let used = lvm
+            .volumes
+            .iter()
+            .map(|v| match v.size {
+                Size::Mib(mib) => mib,
+                Size::Percent(percent) => available * percent / 100,
+                Size::Remainder => 0,
+            })
+            .fold(0, u64::saturating_add);
+        if used > available {
+            return Err(format!(
+                "The logical volumes need {used}MiB but volume group {} only has about {available}MiB",
+                lvm.volume_group
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks the parts of the layout that don't depend on the disk
+    pub fn validate(&self, efi: bool) -> Result<(), String> {
+        if !self.has_mountpoint("/") {
+            return Err(String::from(
+                "The partition layout has no root (/) partition",
+            ));
+        }
+        let mut mountpoints: Vec<&str> = Vec::new();
+        for mountpoint in self.mountpoints() {
+            if !mountpoint.starts_with('/') {
+                return Err(format!("Mountpoint {mountpoint} is not an absolute path"));
+            }
+            if mountpoints.contains(&mountpoint) {
+                return Err(format!("Mountpoint {mountpoint} is used more than once"));
+            }
+            mountpoints.push(mountpoint);
+        }
+        let disk_partitions = self.disks.iter().flat_map(|disk| disk.partitions.iter());
+        for label in self
+            .partitions
+            .iter()
+            .chain(disk_partitions)
+            .filter_map(|p| p.label.as_deref())
+        {
+            check_label(label)?;
+        }
+        let volumes = self.lvm.iter().flat_map(|lvm| lvm.volumes.iter());
+        for filesystem in self
+            .partitions
+            .iter()
+            .map(|p| p.filesystem.as_str())
+            .chain(volumes.map(|v| v.filesystem.as_str()))
+        {
+            partition::check_supported(filesystem)?;
+        }
+        for disk in &self.disks {
+            if disk.partitions.is_empty() {
+                return Err(format!("{} has no partitions", disk.device.display()));
+            }
+            for partition in &disk.partitions {
+                let mountpoint = partition.mountpoint.as_deref().unwrap_or_default();
+                if partition.filesystem == LVM_FILESYSTEM
+                    || partition.is_esp()
+                    || ["/", "/boot"].contains(&mountpoint)
+                {
+                    return Err(format!(
+                        "{} {mountpoint} has to be on the install device, not {}",
+                        partition.filesystem,
+                        disk.device.display()
+                    ));
+                }
+            }
+        }
+        if self.esp.is_some() {
+            if !efi {
+                return Err(String::from(
+                    "An existing EFI system partition can only be used for EFI installs",
+                ));
+            }
+            if !self.partitions.iter().any(|p| p.is_esp()) {
+                return Err(String::from(
+                    "The layout has no EFI system partition to use the existing one in place of",
+                ));
+            }
+        }
+        if self.encryption.as_ref().is_some_and(|e| e.home) && !self.has_mountpoint("/home") {
+            return Err(String::from(
+                "Encrypting /home needs a separate /home partition in the layout",
+            ));
+        }
+        let pvs = self
+            .partitions
+            .iter()
+            .filter(|p| p.filesystem == LVM_FILESYSTEM)
+            .count();
+        match (&self.lvm, pvs) {
+            (None, 0) => {}
+            (None, _) => {
+                return Err(String::from(
+                    "The partition layout has an lvm partition but no logical volumes",
+                ))
+            }
+            (Some(_), 1) => {}
+            (Some(_), _) => {
+                return Err(String::from(
+                    "LVM needs exactly one partition with the lvm filesystem in the layout",
+                ))
+            }
+        }
+        if let Some(lvm) = &self.lvm {
+            lvm.validate()?;
+        }
+        if !self.btrfs.subvolumes.is_empty() {
+            if self.root_filesystem() != Some("btrfs") {
+                return Err(String::from("btrfs subvolumes need a btrfs root"));
+            }
+            let mut names: Vec<&str> = Vec::new();
+            for subvolume in &self.btrfs.subvolumes {
+                if subvolume.name.is_empty() || names.contains(&subvolume.name.as_str()) {
+                    return Err(format!(
+                        "Invalid or duplicate btrfs subvolume name {:?}",
+                        subvolume.name
+                    ));
+                }
+                names.push(&subvolume.name);
+            }
+            if !self.btrfs.subvolumes.iter().any(|s| s.mountpoint == "/") {
+                return Err(String::from("None of the btrfs subvolumes is mounted at /"));
+            }
+        }
+        if self
+            .shrink
+            .as_ref()
+            .is_some_and(|shrink| !matches!(shrink.by, Size::Mib(_)))
+        {
+            return Err(String::from(
+                "Partitions can only be shrunk by an absolute size like 50GiB",
+            ));
+        }
+        let root_filesystem = self.root_filesystem().unwrap_or_default();
+        if root_filesystem != "btrfs" && !self.btrfs.mount_options.is_empty() {
+            return Err(format!(
+                "btrfs mount options can't be used with a {root_filesystem} root"
+            ));
+        }
+        if root_filesystem == "bcachefs" && !self.has_mountpoint("/boot") {
+            return Err(String::from(
+                "GRUB can't read bcachefs, the layout needs a separate /boot partition",
+            ));
+        }
+        if let Some(swap) = &self.swap {
+            if swap.file && root_filesystem == "bcachefs" {
+                return Err(String::from(
+                    "bcachefs doesn't support swapfiles, use a swap partition",
+                ));
+            }
+            if swap.file && !matches!(swap.size, Size::Mib(_)) {
+                return Err(format!(
+                    "A swapfile needs an absolute size like 8GiB, not {}",
+                    swap.size
+                ));
+            }
+            if swap.hibernate && !swap.file && self.encryption.is_some() && self.lvm.is_none() {
+                return Err(String::from(
+                    "Hibernating to a swap partition outside the encrypted root would leak its contents, use a swapfile or LVM",
+                ));
+            }
+        }
+        if let Some(raid) = &self.raid {
+            if raid.devices.len() + 1 < raid.level.min_devices() {
+                return Err(format!(
+                    "{} needs at least {} disks",
+                    raid.level.name(),
+                    raid.level.min_devices()
+                ));
+            }
+            if raid.backend == RaidBackend::Btrfs {
+                if self.encryption.is_some() || self.lvm.is_some() {
+                    return Err(String::from(
+                        "btrfs RAID can't be combined with encryption or LVM, use mdadm instead",
+                    ));
+                }
+                if let Some(partition) = self
+                    .partitions
+                    .iter()
+                    .find(|p| !["btrfs", "vfat", "swap"].contains(&p.filesystem.as_str()))
+                {
+                    return Err(format!(
+                        "btrfs RAID can't hold the {} partition, use btrfs or mdadm instead",
+                        partition.filesystem
+                    ));
+                }
+            }
+        }
+        if !efi && self.partitions.len() > 4 {
+            return Err(String::from(
+                "Non-EFI installs use an msdos partition table which only fits 4 partitions",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!("512MiB".parse(), Ok(Size::Mib(512)));
+        assert_eq!("20G".parse(), Ok(Size::Mib(20 * 1024)));
+        assert_eq!("25%".parse(), Ok(Size::Percent(25)));
+        assert_eq!("rest".parse(), Ok(Size::Remainder));
+        assert!("512MB".parse::<Size>().is_err());
+        assert!("0MiB".parse::<Size>().is_err());
+        assert!("150%".parse::<Size>().is_err());
+        assert!("99999999999TiB".parse::<Size>().is_err());
+    }
+
+    #[test]
+    fn plans_default_layout() {
+        let layout = Layout::default_for(true, true);
+        assert_eq!(
+            layout.plan(20480),
+            Ok(vec![(1, 513), (513, 10049), (10049, 20479)])
+        );
+    }
+
+    #[test]
+    fn moves_root_into_lvm() {
+        let mut layout = Layout::default_for(true, false);
+        layout.use_lvm(None);
+        assert_eq!(layout.validate(true), Ok(()));
+        assert!(layout.has_mountpoint("/"));
+        assert!(layout.holds_root(&layout.partitions[1]));
+        assert!(!layout.holds_root(&layout.partitions[0]));
+    }
+
+    #[test]
+    fn rejects_volumes_too_big_for_the_volume_group() {
+        let mut layout = Layout::default_for(true, false);
+        let mut lvm = Lvm::root_only("ext4");
+        lvm.volumes.push(LogicalVolume {
+            name: String::from("swap"),
+            size: Size::Mib(16384),
+            filesystem: String::from("swap"),
+            mountpoint: None,
+            mkfs_options: Vec::new(),
+        });
+        layout.use_lvm(Some(lvm));
+        assert!(layout.plan(16384).is_err());
+        assert!(layout.plan(32768).is_ok());
+    }
+
+    #[test]
+    fn validates_raid() {
+        let mut layout = Layout::default_for(false, false);
+        layout.raid = Some(Raid {
+            level: RaidLevel::Raid1,
+            backend: RaidBackend::Btrfs,
+            devices: vec![PathBuf::from("/dev/sdb")],
+        });
+        // the default non-EFI layout has an ext4 /boot
+        assert!(layout.validate(false).is_err());
+        layout.raid.as_mut().unwrap().backend = RaidBackend::Mdadm;
+        assert_eq!(layout.validate(false), Ok(()));
+        layout.raid.as_mut().unwrap().level = RaidLevel::Raid10;
+        assert!(layout.validate(false).is_err());
+    }
+
+    #[test]
+    fn defaults_to_root_and_home_subvolumes() {
+        let mut layout = Layout::default_for(true, false);
+        let names = |layout: &Layout| -> Vec<String> {
+            layout.subvolumes().into_iter().map(|s| s.name).collect()
+        };
+        assert_eq!(names(&layout), ["@", "@home"]);
+        layout
+            .partitions
+            .push(LayoutPartition::new(Size::Mib(1024), "ext4", Some("/home")));
+        assert_eq!(names(&layout), ["@"]);
+        layout.btrfs.subvolumes =
+            vec![Subvolume::new("@", "/"), Subvolume::new("@", "/.snapshots")];
+        assert!(layout.validate(true).is_err());
+        layout.btrfs.subvolumes[1].name = String::from("@snapshots");
+        assert_eq!(layout.validate(true), Ok(()));
+        layout.btrfs.subvolumes[1].mountpoint = String::from("/home");
+        assert!(layout.validate(true).is_err());
+    }
+
+    #[test]
+    fn adds_swap() {
+        let mut layout = Layout::default_for(true, false);
+        layout.set_swap(Swap {
+            size: Size::Mib(8192),
+            file: false,
+            hibernate: true,
+        });
+        assert_eq!(layout.partitions[1].filesystem, "swap");
+        assert_eq!(layout.partitions[2].mountpoint.as_deref(), Some("/"));
+
+        let mut layout = Layout::default_for(true, false);
+        layout.set_swap(Swap {
+            size: Size::Percent(10),
+            file: true,
+            hibernate: false,
+        });
+        assert_eq!(layout.partitions.len(), 2);
+        assert_eq!(layout.subvolumes().last().unwrap().mountpoint, "/swap");
+        assert!(layout.validate(true).is_err());
+    }
+
+    #[test]
+    fn plans_into_free_space() {
+        let layout = Layout::default_for(true, false);
+        assert_eq!(
+            layout.plan_free_space(40960, 61440),
+            Ok(vec![(40960, 41472), (41472, 61440)])
+        );
+        assert!(layout.plan_free_space(40960, 41000).is_err());
+    }
+
+    #[test]
+    fn changes_root_filesystem() {
+        let mut layout = Layout::default_for(true, false);
+        layout.set_root_filesystem("ext4");
+        assert_eq!(layout.root_filesystem(), Some("ext4"));
+        assert_eq!(layout.partitions.len(), 2);
+        layout.btrfs.mount_options = vec![String::from("compress=zstd")];
+        assert!(layout.validate(true).is_err());
+
+        let mut layout = Layout::default_for(true, false);
+        layout.use_lvm(None);
+        layout.set_root_filesystem("bcachefs");
+        assert_eq!(layout.root_filesystem(), Some("bcachefs"));
+        assert!(layout.has_mountpoint("/boot"));
+        assert_eq!(layout.partitions[1].mountpoint.as_deref(), Some("/boot"));
+        assert!(layout
+            .validate(true)
+            .is_err_and(|e| e.starts_with("bcachefs is not supported")));
+    }
+
+    #[test]
+    fn validates_labels() {
+        let mut layout = Layout::default_for(true, false);
+        layout.partitions[1].label = Some(String::from("crystal-root"));
+        assert_eq!(layout.validate(true), Ok(()));
+        for label in ["", "my root", "root\"", "it's", &"x".repeat(37)] {
+            layout.partitions[1].label = Some(label.to_string());
+            assert!(layout.validate(true).is_err(), "{label:?}");
+        }
+        layout.partitions[1].label = Some("ä".repeat(36));
+        assert_eq!(layout.validate(true), Ok(()));
+    }
+
+    #[test]
+    fn picks_discoverable_partition_types() {
+        let mut layout = Layout::default_for(true, true);
+        layout.set_swap(Swap {
+            size: Size::Mib(4096),
+            file: false,
+            hibernate: false,
+        });
+        let types: Vec<_> = layout
+            .partitions
+            .iter()
+            .map(|p| layout.gpt_type(p))
+            .collect();
+        let labels: Vec<_> = layout
+            .partitions
+            .iter()
+            .map(|p| layout.gpt_label(p))
+            .collect();
+        assert_eq!(
+            types,
+            [
+                gpt_type::ESP,
+                gpt_type::LINUX,
+                gpt_type::SWAP,
+                gpt_type::ROOT
+            ]
+        );
+        assert_eq!(labels, ["esp", "unakite", "swap", "root"]);
+
+        layout.raid = Some(Raid {
+            level: RaidLevel::Raid1,
+            backend: RaidBackend::Mdadm,
+            devices: vec![PathBuf::from("/dev/sdb")],
+        });
+        assert_eq!(layout.gpt_type(&layout.partitions[3]), gpt_type::RAID);
+        assert_eq!(layout.gpt_type(&layout.partitions[0]), gpt_type::ESP);
+    }
+
+    #[test]
+    fn puts_home_on_another_disk() {
+        let mut layout = Layout::default_for(true, false);
+        layout.disks.push(DiskLayout {
+            device: PathBuf::from("/dev/sdb"),
+            partitions: vec![LayoutPartition::new(Size::Remainder, "ext4", Some("/home"))],
+        });
+        assert_eq!(layout.validate(true), Ok(()));
+        let subvolumes: Vec<String> = layout.subvolumes().into_iter().map(|s| s.name).collect();
+        assert_eq!(subvolumes, ["@"]);
+        let disk = layout.for_disk(&layout.disks[0]);
+        assert_eq!(disk.plan(1000), Ok(vec![(1, 999)]));
+
+        layout.disks[0].partitions[0].mountpoint = Some(String::from("/"));
+        assert!(layout.validate(true).is_err());
+    }
+
+    #[test]
+    fn reuses_an_existing_esp() {
+        assert_eq!("auto".parse(), Ok(ExistingEsp::Detect));
+        assert_eq!(
+            "/dev/nvme0n1p1".parse(),
+            Ok(ExistingEsp::Device(PathBuf::from("/dev/nvme0n1p1")))
+        );
+        let mut layout = Layout::default_for(true, false);
+        layout.esp = Some(ExistingEsp::Detect);
+        assert_eq!(layout.validate(true), Ok(()));
+        assert!(layout.validate(false).is_err());
+        assert_eq!(layout.take_esp().as_deref(), Some("/efi"));
+        assert_eq!(layout.partitions.len(), 1);
+        assert!(layout.validate(true).is_err());
+    }
+
+    #[test]
+    fn rejects_layouts_too_big_for_the_disk() {
+        let layout = Layout::default_for(true, true);
+        assert!(layout.plan(8192).is_err());
+    }
+}
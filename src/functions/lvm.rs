@@ -0,0 +1,67 @@
+use crate::functions::layout::{LogicalVolume, Lvm, Size};
+use crate::internal::exec::*;
+use crate::internal::state;
+use crate::internal::*;
+
+/// The lvcreate arguments that give a logical volume its size
+fn size_args(size: Size) -> Vec<String> {
+    match size {
+        Size::Mib(mib) => vec![String::from("-L"), format!("{mib}M")],
+        Size::Percent(percent) => vec![String::from("-l"), format!("{percent}%VG")],
+        Size::Remainder => vec![String::from("-l"), String::from("100%FREE")],
+    }
+}
+
+/// Turns `blockdevice` into the only physical volume of a new volume group and creates
+/// the logical volumes of `lvm` on it. Returns each volume with its block device.
+pub fn setup_lvm<'a>(blockdevice: &str, lvm: &'a Lvm) -> Vec<(&'a LogicalVolume, String)> {
+    let vg = &lvm.volume_group;
+    exec_eval(
+        exec(
+            "pvcreate",
+            vec![
+                String::from("-ff"),
+                String::from("-y"),
+                String::from(blockdevice),
+            ],
+        ),
+        format!("Create LVM physical volume on {blockdevice}").as_str(),
+    );
+    exec_eval(
+        exec("vgcreate", vec![vg.clone(), String::from(blockdevice)]),
+        format!("Create volume group {vg}").as_str(),
+    );
+    // whatever takes up the rest of the volume group has to be created last
+    let mut volumes: Vec<&LogicalVolume> = lvm.volumes.iter().collect();
+    volumes.sort_by_key(|v| v.size == Size::Remainder);
+    for volume in &volumes {
+        let mut args = vec![String::from("-y"), String::from("-n"), volume.name.clone()];
+        args.extend(size_args(volume.size));
+        args.push(vg.clone());
+        exec_eval(
+            exec("lvcreate", args),
+            format!(
+                "Create logical volume {vg}/{} of {}",
+                volume.name, volume.size
+            )
+            .as_str(),
+        );
+    }
+    state::update(|state| {
+        state.add_initramfs_hook("lvm2");
+        state.add_package("lvm2");
+    });
+    lvm.volumes
+        .iter()
+        .map(|volume| (volume, format!("/dev/{vg}/{}", volume.name)))
+        .collect()
+}
+
+/// Makes the installed system able to boot from an existing logical volume
+pub fn use_existing_volume(blockdevice: &str) {
+    log::info!("{blockdevice} is an LVM logical volume, adding LVM support to the initramfs");
+    state::update(|state| {
+        state.add_initramfs_hook("lvm2");
+        state.add_package("lvm2");
+    });
+}
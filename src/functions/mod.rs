@@ -1,7 +1,17 @@
 pub mod base;
 pub mod desktops;
+pub mod disks;
+pub mod dualboot;
+pub mod encryption;
+pub mod fstab;
+pub mod image;
+pub mod initramfs;
+pub mod layout;
 pub mod locale;
+pub mod lvm;
 pub mod network;
 pub mod partition;
+pub mod raid;
+pub mod swap;
 pub mod unakite;
 pub mod users;
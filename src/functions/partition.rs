@@ -1,665 +1,725 @@
 use crate::args;
-use crate::args::PartitionMode;
+use crate::args::{PartitionMode, RaidBackend};
+use crate::functions::layout::{ExistingEsp, Layout, Subvolume, LVM_FILESYSTEM, UNAKITE_LABEL};
+use crate::functions::{disks, dualboot, encryption, lvm, raid, swap};
 use crate::internal::exec::*;
+use crate::internal::state;
 use crate::internal::*;
 use std::path::{Path, PathBuf};
 
-/*mkfs.bfs mkfs.cramfs mkfs.ext3  mkfs.fat mkfs.msdos  mkfs.xfs
-mkfs.btrfs mkfs.ext2  mkfs.ext4  mkfs.minix mkfs.vfat mkfs.f2fs */
+/// The filesystems `format` knows with their mkfs command, the arguments it always gets
+/// before the options of the partition and the package it comes from, if the base system
+/// doesn't include it
+const MKFS: [(&str, &str, &[&str], Option<&str>); 14] = [
+    ("vfat", "mkfs.vfat", &["-F32"], None),
+    ("bfs", "mkfs.bfs", &[], None),
+    ("cramfs", "mkfs.cramfs", &[], None),
+    ("ext3", "mkfs.ext3", &[], None),
+    ("fat", "mkfs.fat", &[], None),
+    ("msdos", "mkfs.msdos", &[], None),
+    ("xfs", "mkfs.xfs", &[], Some("xfsprogs")),
+    ("btrfs", "mkfs.btrfs", &["-f"], None),
+    ("ext2", "mkfs.ext2", &[], None),
+    ("ext4", "mkfs.ext4", &[], None),
+    ("minix", "mkfs.minix", &[], None),
+    ("f2fs", "mkfs.f2fs", &[], Some("f2fs-tools")),
+    ("bcachefs", "mkfs.bcachefs", &[], Some("bcachefs-tools")),
+    ("swap", "mkswap", &[], None),
+];
 
-pub fn fmt_mount(mountpoint: &str, filesystem: &str, blockdevice: &str) {
-    match filesystem {
-        "vfat" => exec_eval(
-            exec(
-                "mkfs.vfat",
-                vec![String::from("-F32"), String::from(blockdevice)],
-            ),
-            format!("Formatting {blockdevice} as vfat").as_str(),
-        ),
-        "bfs" => exec_eval(
-            exec("mkfs.bfs", vec![String::from(blockdevice)]),
-            format!("Formatting {blockdevice} as bfs").as_str(),
-        ),
-        "cramfs" => exec_eval(
-            exec("mkfs.cramfs", vec![String::from(blockdevice)]),
-            format!("Formatting {blockdevice} as cramfs").as_str(),
-        ),
-        "ext3" => exec_eval(
-            exec("mkfs.ext3", vec![String::from(blockdevice)]),
-            format!("Formatting {blockdevice} as ext3").as_str(),
-        ),
-        "fat" => exec_eval(
-            exec("mkfs.fat", vec![String::from(blockdevice)]),
-            format!("Formatting {blockdevice} as fat").as_str(),
-        ),
-        "msdos" => exec_eval(
-            exec("mkfs.msdos", vec![String::from(blockdevice)]),
-            format!("Formatting {blockdevice} as msdos").as_str(),
-        ),
-        "xfs" => exec_eval(
-            exec("mkfs.xfs", vec![String::from(blockdevice)]),
-            format!("Formatting {blockdevice} as xfs").as_str(),
-        ),
-        "btrfs" => exec_eval(
-            exec(
-                "mkfs.btrfs",
-                vec![String::from("-f"), String::from(blockdevice)],
-            ),
-            format!("Formatting {blockdevice} as btrfs").as_str(),
-        ),
-        "ext2" => exec_eval(
-            exec("mkfs.ext2", vec![String::from(blockdevice)]),
-            format!("Formatting {blockdevice} as ext2").as_str(),
-        ),
-        "ext4" => exec_eval(
-            exec("mkfs.ext4", vec![String::from(blockdevice)]),
-            format!("Formatting {blockdevice} as ext4").as_str(),
-        ),
-        "minix" => exec_eval(
-            exec("mkfs.minix", vec![String::from(blockdevice)]),
-            format!("Formatting {blockdevice} as minix").as_str(),
-        ),
-        "f2fs" => exec_eval(
-            exec("mkfs.f2fs", vec![String::from(blockdevice)]),
-            format!("Formatting {blockdevice} as f2fs").as_str(),
-        ),
-        "don't format" => {
-            log::debug!("Not formatting {}", blockdevice);
-        }
-        "noformat" => {
-            log::debug!("Not formatting {}", blockdevice);
-        }
-        _ => {
-            crash(
-                format!("Unknown filesystem {filesystem}, used in partition {blockdevice}"),
-                1,
-            );
-        }
+/// Used instead of a filesystem to keep what's on the partition
+pub const NOFORMAT: [&str; 2] = ["don't format", "noformat"];
+
+/// Filesystems mkfs can create but the kernel of the installed system can't mount
+const UNSUPPORTED: [(&str, &str); 1] = [(
+    "bcachefs",
+    "bcachefs was dropped from the mainline kernel in 6.18, so the installed system couldn't mount it",
+)];
+
+/// Errors if the installed system couldn't mount `filesystem`
+pub fn check_supported(filesystem: &str) -> Result<(), String> {
+    match UNSUPPORTED.iter().find(|(name, _)| *name == filesystem) {
+        Some((_, reason)) => Err(format!("{filesystem} is not supported: {reason}")),
+        None => Ok(()),
+    }
+}
+
+/// The filesystems `format` accepts, including `NOFORMAT`
+pub fn filesystems() -> Vec<&'static str> {
+    MKFS.iter()
+        .map(|(filesystem, ..)| *filesystem)
+        .chain(NOFORMAT)
+        .collect()
+}
+
+/// Formats `blockdevice` as `filesystem`, passing `options` on to mkfs
+pub fn format(filesystem: &str, blockdevice: &str, options: &[String]) {
+    if NOFORMAT.contains(&filesystem) {
+        log::debug!("Not formatting {}", blockdevice);
+        return;
     }
+    let Some((_, command, args, package)) = MKFS.iter().find(|(name, ..)| *name == filesystem)
+    else {
+        crash(
+            format!("Unknown filesystem {filesystem}, used in partition {blockdevice}"),
+            1,
+        );
+    };
+    let mut args: Vec<String> = args.iter().map(|arg| String::from(*arg)).collect();
+    args.extend(options.iter().cloned());
+    args.push(String::from(blockdevice));
     exec_eval(
-        exec("mkdir", vec![String::from("-p"), String::from(mountpoint)]),
-        format!("Creating mountpoint {mountpoint} for {blockdevice}").as_str(),
+        exec(command, args),
+        format!("Formatting {blockdevice} as {filesystem}").as_str(),
     );
-    mount(blockdevice, mountpoint, "");
+    if let Some(package) = package {
+        state::update(|state| state.add_package(package));
+    }
+}
+
+/// How `device` is named when asking to confirm changes to it, an image file by its path
+/// rather than the loop device jade attached it to
+fn confirmation_name(device: &Path) -> String {
+    let state = state::load();
+    match (state.image, state.loop_device) {
+        (Some(image), Some(loop_device)) if Path::new(&loop_device) == device => image,
+        _ => device.display().to_string(),
+    }
 }
 
 pub fn partition(
     device: PathBuf,
     mode: PartitionMode,
     efi: bool,
-    partitions: &mut Vec<args::Partition>,
-    unakite: bool,
+    partitions: &[args::Partition],
+    layout: &Layout,
+    yes: bool,
 ) {
-    println!("{:?}", mode);
+    state::reset();
     match mode {
         PartitionMode::Auto => {
-            if !device.exists() {
-                crash(format!("The device {device:?} doesn't exist"), 1);
+            let mut devices = vec![device.clone()];
+            if let Some(raid) = &layout.raid {
+                devices.extend(raid.devices.iter().cloned());
             }
-            log::debug!("automatically partitioning {device:?}");
-            if efi {
-                partition_with_efi(&device, unakite);
-            } else {
-                partition_no_efi(&device, unakite);
+            let all: Vec<&PathBuf> = devices
+                .iter()
+                .chain(layout.disks.iter().map(|disk| &disk.device))
+                .collect();
+            for (i, device) in all.iter().enumerate() {
+                if !device.exists() {
+                    crash(format!("The device {device:?} doesn't exist"), 1);
+                }
+                if all[..i].contains(device) {
+                    crash(format!("The device {device:?} is used more than once"), 1);
+                }
             }
-            if device.to_string_lossy().contains("nvme")
-                || device.to_string_lossy().contains("mmcblk")
-            {
-                part_nvme(&device, efi, unakite);
-            } else {
-                part_disk(&device, efi, unakite);
+            if let Err(e) = layout.validate(efi) {
+                crash(format!("Invalid partition layout: {e}"), 1);
+            }
+            let mut layout = layout.clone();
+            let esp = layout.esp.clone().map(|existing| {
+                let esp = existing_esp(&existing, &all);
+                let mountpoint = layout.take_esp().unwrap_or_default();
+                (esp, mountpoint)
+            });
+            let layout = &layout;
+            // every disk of a RAID gets the same partitions, so the smallest one decides
+            let (smallest, disk_mib) = devices
+                .iter()
+                .map(|device| (device, disks::disk_size_mib(device)))
+                .min_by_key(|(_, size)| *size)
+                .unwrap();
+            let plan = match layout.plan(disk_mib) {
+                Ok(plan) => plan,
+                Err(e) => crash(format!("Invalid partition layout for {smallest:?}: {e}"), 1),
+            };
+            let disk_plans: Vec<Vec<(u64, u64)>> = layout
+                .disks
+                .iter()
+                .map(|disk| {
+                    match layout
+                        .for_disk(disk)
+                        .plan(disks::disk_size_mib(&disk.device))
+                    {
+                        Ok(plan) => plan,
+                        Err(e) => crash(
+                            format!("Invalid partition layout for {:?}: {e}", disk.device),
+                            1,
+                        ),
+                    }
+                })
+                .collect();
+            for device in &all {
+                if let Err(e) = disks::check_safe_to_wipe(device) {
+                    crash(
+                        format!("Refusing to partition {}: {e}", device.display()),
+                        1,
+                    );
+                }
+            }
+            let names: Vec<String> = devices.iter().map(|d| d.display().to_string()).collect();
+            let all_names: Vec<String> = all.iter().map(|d| d.display().to_string()).collect();
+            let confirmations: Vec<String> = all.iter().map(|d| confirmation_name(d)).collect();
+            confirm::confirm_destructive(
+                &format!("All data on {} will be erased!", confirmations.join(", ")),
+                &confirmation_name(&device),
+                yes,
+            );
+            log::debug!("automatically partitioning {all_names:?}");
+            let blockdevices: Vec<Vec<String>> = devices
+                .iter()
+                .map(|device| partition_layout(device, efi, layout, &plan))
+                .collect();
+            let disk_blockdevices: Vec<Vec<String>> = layout
+                .disks
+                .iter()
+                .zip(&disk_plans)
+                .map(|(disk, plan)| {
+                    partition_layout(&disk.device, efi, &layout.for_disk(disk), plan)
+                })
+                .collect();
+            format_mount_layout(layout, &blockdevices, &disk_blockdevices);
+            if let Some((blockdevice, mountpoint)) = esp {
+                // it's shared with another OS, so GRUB looks for it too
+                state::update(|state| state.dual_boot = true);
+                mount_targets(vec![(mountpoint, blockdevice, String::new())]);
+            }
+            if let Some(raid) = layout.raid.as_ref().filter(|raid| raid.level.redundant()) {
+                if !efi {
+                    state::update(|state| state.mirror_disks = names[1..].to_vec());
+                }
+                log::info!(
+                    "The bootloader gets installed on all {} disks of the {}",
+                    devices.len(),
+                    raid.level.name()
+                );
             }
         }
+        PartitionMode::Alongside => partition_alongside(&device, efi, layout, yes),
         PartitionMode::Manual => {
             log::debug!("Manual partitioning");
-            partitions.sort_by(|a, b| a.mountpoint.len().cmp(&b.mountpoint.len()));
-            for i in 0..partitions.len() {
-                println!("{:?}", partitions);
-                println!("{}", partitions.len());
-                println!("{}", &partitions[i].mountpoint);
-                println!("{}", &partitions[i].filesystem);
-                println!("{}", &partitions[i].blockdevice);
-                fmt_mount(
-                    &partitions[i].mountpoint,
-                    &partitions[i].filesystem,
-                    &partitions[i].blockdevice,
+            let existing: Vec<String> = partitions
+                .iter()
+                .filter(|p| !NOFORMAT.contains(&p.filesystem.as_str()))
+                .filter_map(|p| {
+                    let data = disks::existing_data(Path::new(&p.blockdevice))?;
+                    Some(format!("{}: {data}", p.blockdevice))
+                })
+                .collect();
+            if !existing.is_empty() {
+                confirm::confirm_destructive(
+                    &format!(
+                        "Formatting will destroy what is on these partitions!\n{}",
+                        existing.join("\n")
+                    ),
+                    // `device` isn't what gets formatted in manual mode
+                    "yes",
+                    yes,
                 );
             }
+            let mut targets = Vec::new();
+            for partition in partitions {
+                let blockdevice = &partition.blockdevice;
+                if partition.filesystem == LVM_FILESYSTEM {
+                    let Some(lvm) = &layout.lvm else {
+                        crash(format!("No logical volumes are given for {blockdevice}"), 1);
+                    };
+                    for (volume, device) in lvm::setup_lvm(blockdevice, lvm) {
+                        format(&volume.filesystem, &device, &volume.mkfs_options);
+                        match &volume.mountpoint {
+                            Some(mountpoint)
+                                if mountpoint == "/" && volume.filesystem == "btrfs" =>
+                            {
+                                targets.extend(create_btrfs_subvolumes(
+                                    &device,
+                                    layout.subvolumes(),
+                                    &layout.btrfs.mount_options,
+                                ));
+                            }
+                            Some(mountpoint) => {
+                                targets.push((mountpoint.clone(), device, String::new()))
+                            }
+                            None if volume.filesystem == "swap" => swapon(&device),
+                            None => {}
+                        }
+                    }
+                    continue;
+                }
+                if disks::is_lvm_volume(Path::new(blockdevice)) {
+                    lvm::use_existing_volume(blockdevice);
+                }
+                let mut options = partition.mkfs_options.clone();
+                if let Some(label) = &partition.label {
+                    options.extend(label_args(&partition.filesystem, label));
+                }
+                format(&partition.filesystem, blockdevice, &options);
+                if partition.filesystem == "swap" {
+                    swapon(blockdevice);
+                    continue;
+                }
+                if !partition
+                    .subvolumes
+                    .iter()
+                    .any(|s| s.mountpoint == partition.mountpoint)
+                {
+                    targets.push((
+                        partition.mountpoint.clone(),
+                        blockdevice.clone(),
+                        partition.mount_options.join(","),
+                    ));
+                }
+                if !partition.subvolumes.is_empty() {
+                    targets.extend(create_btrfs_subvolumes(
+                        blockdevice,
+                        partition.subvolumes.clone(),
+                        &partition.mount_options,
+                    ));
+                }
+            }
+            mount_targets(targets);
         }
     }
 }
 
-fn partition_no_efi(device: &Path, unakite: bool) {
-    let device = device.to_string_lossy().to_string();
-    exec_eval(
-        exec(
-            "parted",
-            vec![
-                String::from("-s"),
-                String::from(&device),
-                String::from("mklabel"),
-                String::from("msdos"),
-            ],
-        ),
-        format!("Create msdos label on {}", device).as_str(),
-    );
-    exec_eval(
-        exec(
-            "parted",
-            vec![
-                String::from("-s"),
-                String::from(&device),
-                String::from("mkpart"),
-                String::from("primary"),
-                String::from("ext4"),
-                String::from("1MIB"),
-                String::from("512MIB"),
-            ],
-        ),
-        "create bios boot partition",
-    );
-    if unakite {
-        exec_eval(
-            exec(
-                "parted",
-                vec![
-                    String::from("-s"),
-                    String::from(&device),
-                    String::from("mkpart"),
-                    String::from("primary"),
-                    String::from("btrfs"),
-                    String::from("512MIB"),
-                    String::from("10048MIB"),
-                ],
+/// Finds the existing EFI system partition to use and checks that it can be used,
+/// `disks` are the disks that get erased
+fn existing_esp(existing: &ExistingEsp, disks: &[&PathBuf]) -> String {
+    let esp = match existing {
+        ExistingEsp::Device(device) => device.to_string_lossy().to_string(),
+        ExistingEsp::Detect => {
+            let disks: Vec<&Path> = disks.iter().map(|disk| disk.as_path()).collect();
+            match disks::find_esps(&disks).as_slice() {
+                [esp] => esp.clone(),
+                [] => crash("No other disk has an EFI system partition to use", 1),
+                esps => crash(
+                    format!(
+                        "Found the EFI system partitions {}, pick one of them",
+                        esps.join(", ")
+                    ),
+                    1,
+                ),
+            }
+        }
+    };
+    if !Path::new(&esp).exists() {
+        crash(format!("The EFI system partition {esp} doesn't exist"), 1);
+    }
+    if let Some(disk) = disks
+        .iter()
+        .find(|disk| disks::is_partition_of(Path::new(&esp), disk))
+    {
+        crash(
+            format!(
+                "The EFI system partition {esp} is on {}, which gets erased, install alongside instead",
+                disk.display()
             ),
-            "create btrfs Unakite root partition",
+            1,
         );
-        exec_eval(
-            exec(
-                "parted",
-                vec![
-                    String::from("-s"),
-                    device,
-                    String::from("mkpart"),
-                    String::from("primary"),
-                    String::from("btrfs"),
-                    String::from("10048MIB"),
-                    String::from("100%"),
-                ],
+    }
+    if let Err(e) = dualboot::check_esp(&esp) {
+        crash(format!("Can't use the EFI system partition: {e}"), 1);
+    }
+    log::info!("Using the existing EFI system partition {esp}");
+    esp
+}
+
+/// Puts the partitions of `layout` into the free space of a disk that already has another OS,
+/// optionally shrinking one of its partitions first. An existing EFI system partition is
+/// mounted instead of creating a new one.
+fn partition_alongside(device: &Path, efi: bool, layout: &Layout, yes: bool) {
+    if !device.exists() {
+        crash(format!("The device {device:?} doesn't exist"), 1);
+    }
+    if let Err(e) = layout.validate(efi) {
+        crash(format!("Invalid partition layout: {e}"), 1);
+    }
+    if let Err(e) = dualboot::check_layout(layout) {
+        crash(e, 1);
+    }
+    if let Err(e) = disks::check_safe_to_wipe(device) {
+        crash(
+            format!("Refusing to partition {}: {e}", device.display()),
+            1,
+        );
+    }
+    let mut table = dualboot::read_table(device);
+    if table.label != "gpt" && table.label != "msdos" {
+        crash(
+            format!(
+                "{} has no partition table to install alongside of, use auto mode instead",
+                device.display()
             ),
-            "create btrfs Crystal root partition",
+            1,
         );
-    } else {
-        exec_eval(
-            exec(
-                "parted",
-                vec![
-                    String::from("-s"),
-                    device,
-                    String::from("mkpart"),
-                    String::from("primary"),
-                    String::from("btrfs"),
-                    String::from("512MIB"),
-                    String::from("100%"),
-                ],
+    }
+    let mut layout = layout.clone();
+    // (block device, mountpoint) of the EFI system partition of the other OS
+    let mut esp = None;
+    if efi {
+        if let Some(existing) = dualboot::find_esp(device, &table) {
+            if let Some(mountpoint) = layout.take_esp() {
+                if let Err(e) = dualboot::check_esp(&existing) {
+                    crash(format!("Can't reuse the EFI system partition: {e}"), 1);
+                }
+                log::info!("Reusing the existing EFI system partition {existing}");
+                esp = Some((existing, mountpoint));
+            }
+        }
+    }
+    if let Some(shrink) = &layout.shrink {
+        confirm::confirm_destructive(
+            &format!(
+                "Partition {} of {} will be shrunk by {}, make sure its data is backed up!",
+                shrink.partition,
+                confirmation_name(device),
+                shrink.by
+            ),
+            &confirmation_name(device),
+            yes,
+        );
+        dualboot::shrink_partition(device, &table, shrink);
+        table = dualboot::read_table(device);
+    }
+    if table.label == "msdos" && table.partitions().count() + layout.partitions.len() > 4 {
+        crash(
+            format!(
+                "{} uses an msdos partition table which has no room for {} more partitions",
+                device.display(),
+                layout.partitions.len()
             ),
-            "create btrfs root partition",
+            1,
         );
     }
+    let Some((start, end)) = table.largest_free_space() else {
+        crash(
+            format!(
+                "{} has no free space, shrink a partition first",
+                device.display()
+            ),
+            1,
+        );
+    };
+    let plan = match layout.plan_free_space(start, end) {
+        Ok(plan) => plan,
+        Err(e) => crash(
+            format!("The free space from {start}MiB to {end}MiB is too small: {e}"),
+            1,
+        ),
+    };
+    log::debug!("partitioning the free space of {device:?} from {start}MiB to {end}MiB");
+    let blockdevices = create_partitions(device, table.label == "gpt", &layout, &plan);
+    state::update(|state| state.dual_boot = true);
+    format_mount_layout(&layout, &[blockdevices], &[]);
+    if let Some((blockdevice, mountpoint)) = esp {
+        mount_targets(vec![(mountpoint, blockdevice, String::new())]);
+    }
+}
+
+/// The mkfs arguments that set the filesystem label
+fn label_args(filesystem: &str, label: &str) -> Vec<String> {
+    let flag = match filesystem {
+        "vfat" | "fat" | "msdos" => "-n",
+        "f2fs" => "-l",
+        _ => "-L",
+    };
+    vec![String::from(flag), String::from(label)]
+}
+
+/// The filesystem type parted uses to pick the partition type, if it matters for `filesystem`
+fn parted_fs_type(filesystem: &str) -> Option<&'static str> {
+    match filesystem {
+        "vfat" | "fat" | "msdos" => Some("fat32"),
+        "swap" => Some("linux-swap"),
+        _ => None,
+    }
 }
 
-fn partition_with_efi(device: &Path, unakite: bool) {
-    let device = device.to_string_lossy().to_string();
+/// Creates a new partition table on `device` with all partitions of `layout`,
+/// returns the block devices of the new partitions
+fn partition_layout(device: &Path, efi: bool, layout: &Layout, plan: &[(u64, u64)]) -> Vec<String> {
+    let device_str = device.to_string_lossy().to_string();
+    if let Some(method) = layout.wipe {
+        disks::wipe(device, method);
+    }
+    let table = if efi { "gpt" } else { "msdos" };
     exec_eval(
         exec(
             "parted",
             vec![
                 String::from("-s"),
-                String::from(&device),
+                device_str.clone(),
                 String::from("mklabel"),
-                String::from("gpt"),
+                String::from(table),
             ],
         ),
-        format!("create gpt label on {}", &device).as_str(),
+        format!("Create {table} label on {device_str}").as_str(),
     );
+    let blockdevices = create_partitions(device, efi, layout, plan);
+    if !efi {
+        // some BIOSes only boot from disks with an active partition, make that the one with /boot
+        let boot = layout
+            .partitions
+            .iter()
+            .position(|p| p.mountpoint.as_deref() == Some("/boot"))
+            .or_else(|| layout.partitions.iter().position(|p| layout.holds_root(p)));
+        if let Some(i) = boot {
+            let (number, _) = disks::partition_at(device, plan[i].0);
+            set_flag(&device_str, number, "boot");
+        }
+    }
+    blockdevices
+}
+
+/// Turns on a parted flag like `esp` or `boot` of partition `number`
+fn set_flag(device: &str, number: usize, flag: &str) {
     exec_eval(
         exec(
             "parted",
             vec![
                 String::from("-s"),
-                String::from(&device),
-                String::from("mkpart"),
-                String::from("fat32"),
-                String::from("0%"),     // start at 0% for auto-alignment
-                String::from("512MIB"), // bumped partition size from 300 Mb to 500 Mb to give us plenty of wiggle room with refind
+                String::from(device),
+                String::from("set"),
+                number.to_string(),
+                String::from(flag),
+                String::from("on"),
             ],
         ),
-        "create EFI partition",
+        format!("set the {flag} flag on partition {number} of {device}").as_str(),
     );
-    if unakite {
-        exec_eval(
-            exec(
-                "parted",
-                vec![
-                    String::from("-s"),
-                    String::from(&device),
-                    String::from("mkpart"),
-                    String::from("primary"),
-                    String::from("btrfs"),
-                    String::from("512MIB"),
-                    String::from("10048MIB"),
-                ],
-            ),
-            "create btrfs Unakite root partition",
-        );
-        exec_eval(
-            exec(
-                "parted",
-                vec![
-                    String::from("-s"),
-                    device,
-                    String::from("mkpart"),
-                    String::from("primary"),
-                    String::from("btrfs"),
-                    String::from("10048MIB"),
-                    String::from("100%"),
-                ],
-            ),
-            "create btrfs Crystal root partition",
-        );
-    } else {
+}
+
+/// Creates the partitions of `layout` where `plan` says on a disk that already has a partition
+/// table, `gpt` is whether it's a GPT or an msdos one. On GPT they get type GUIDs and names
+/// after what they're used for. Returns the block devices of the new partitions.
+fn create_partitions(
+    device: &Path,
+    gpt: bool,
+    layout: &Layout,
+    plan: &[(u64, u64)],
+) -> Vec<String> {
+    let device_str = device.to_string_lossy().to_string();
+    for (partition, (start, end)) in layout.partitions.iter().zip(plan.iter().copied()) {
+        // on msdos tables the name parameter of mkpart is the partition kind instead
+        let name = if gpt {
+            layout.gpt_label(partition)
+        } else {
+            String::from("primary")
+        };
+        let mut args = vec![
+            String::from("-s"),
+            device_str.clone(),
+            String::from("mkpart"),
+            name,
+        ];
+        if let Some(fs_type) = parted_fs_type(&partition.filesystem) {
+            args.push(String::from(fs_type));
+        }
+        args.push(format!("{start}MiB"));
+        args.push(format!("{end}MiB"));
         exec_eval(
-            exec(
-                "parted",
-                vec![
-                    String::from("-s"),
-                    device,
-                    String::from("mkpart"),
-                    String::from("primary"),
-                    String::from("btrfs"),
-                    String::from("512MIB"),
-                    String::from("100%"),
-                ],
-            ),
-            "create btrfs root partition",
+            exec("parted", args),
+            format!(
+                "create {} partition from {start}MiB to {end}MiB",
+                partition.filesystem
+            )
+            .as_str(),
         );
     }
+    disks::settle();
+    let mut blockdevices = Vec::new();
+    for (partition, (start, _)) in layout.partitions.iter().zip(plan.iter().copied()) {
+        let (number, blockdevice) = disks::partition_at(device, start);
+        let r#type = if gpt {
+            Some(layout.gpt_type(partition))
+        } else {
+            partition.r#type.clone()
+        };
+        if let Some(r#type) = &r#type {
+            exec_eval(
+                exec(
+                    "parted",
+                    vec![
+                        String::from("-s"),
+                        device_str.clone(),
+                        String::from("type"),
+                        number.to_string(),
+                        r#type.clone(),
+                    ],
+                ),
+                format!("set type of partition {number} to {type}").as_str(),
+            );
+        }
+        if gpt && partition.is_esp() {
+            set_flag(&device_str, number, "esp");
+        }
+        blockdevices.push(blockdevice);
+    }
+    blockdevices
 }
 
-fn part_nvme(device: &Path, efi: bool, unakite: bool) {
-    let device = device.to_string_lossy().to_string();
-    if efi && !unakite {
-        exec_eval(
-            exec(
-                "mkfs.vfat",
-                vec![String::from("-F32"), format!("{}p1", device)],
-            ),
-            format!("format {}p1 as fat32", device).as_str(),
-        );
-        exec_eval(
-            exec(
-                "mkfs.btrfs",
-                vec!["-f".to_string(), format!("{}p2", device)],
-            ),
-            format!("format {}p2 as btrfs", device).as_str(),
-        );
-        mount(format!("{}p2", device).as_str(), "/mnt", "");
-        exec_eval(
-            exec_workdir(
-                "btrfs",
-                "/mnt",
-                vec![
-                    String::from("subvolume"),
-                    String::from("create"),
-                    String::from("@"),
-                ],
-            ),
-            "Create btrfs subvolume @",
-        );
-        exec_eval(
-            exec_workdir(
-                "btrfs",
-                "/mnt",
-                vec![
-                    String::from("subvolume"),
-                    String::from("create"),
-                    String::from("@home"),
-                ],
-            ),
-            "Create btrfs subvolume @home",
-        );
-        umount("/mnt");
-        mount(format!("{}p2", device).as_str(), "/mnt/", "subvol=@");
-        files_eval(files::create_directory("/mnt/boot"), "create /mnt/boot");
-        files_eval(files::create_directory("/mnt/home"), "create /mnt/home");
-        mount(
-            format!("{}p2", device).as_str(),
-            "/mnt/home",
-            "subvol=@home",
-        );
-        files_eval(files::create_directory("/mnt/efi"), "create /mnt/efi");
-        mount(format!("{}p1", device).as_str(), "/mnt/efi", "");
-    } else if !efi && !unakite {
-        exec_eval(
-            exec("mkfs.ext4", vec![format!("{}p1", device)]),
-            format!("format {}p1 as ext4", device).as_str(),
-        );
-        exec_eval(
-            exec(
-                "mkfs.btrfs",
-                vec!["-f".to_string(), format!("{}p2", device)],
-            ),
-            format!("format {}p2 as btrfs", device).as_str(),
-        );
-        mount(format!("{}p2", device).as_str(), "/mnt/", "");
-        exec_eval(
-            exec_workdir(
-                "btrfs",
-                "/mnt",
-                vec![
-                    String::from("subvolume"),
-                    String::from("create"),
-                    String::from("@"),
-                ],
-            ),
-            "Create btrfs subvolume @",
-        );
-        exec_eval(
-            exec_workdir(
-                "btrfs",
-                "/mnt",
-                vec![
-                    String::from("subvolume"),
-                    String::from("create"),
-                    String::from("@home"),
-                ],
-            ),
-            "Create btrfs subvolume @home",
-        );
-        umount("/mnt");
-        mount(format!("{}p2", device).as_str(), "/mnt/", "subvol=@");
-        files_eval(files::create_directory("/mnt/boot"), "create /mnt/boot");
-        files_eval(files::create_directory("/mnt/home"), "create /mnt/home");
-        mount(
-            format!("{}p2", device).as_str(),
-            "/mnt/home",
-            "subvol=@home",
-        );
-        mount(format!("{}p1", device).as_str(), "/mnt/boot", "");
-    } else if efi && unakite {
-        exec_eval(
-            exec(
-                "mkfs.vfat",
-                vec![String::from("-F32"), format!("{}p1", device)],
-            ),
-            format!("format {}p1 as fat32", device).as_str(),
-        );
-        exec_eval(
-            exec(
-                "mkfs.btrfs",
-                vec!["-f".to_string(), format!("{}p2", device)],
-            ),
-            format!("format {}p2 as btrfs", device).as_str(),
-        );
-        exec_eval(
-            exec(
-                "mkfs.btrfs",
-                vec!["-f".to_string(), format!("{}p3", device)],
-            ),
-            format!("format {}p3 as btrfs", device).as_str(),
-        );
-        mount(format!("{}p3", device).as_str(), "/mnt", "");
-        exec_eval(
-            exec_workdir(
-                "btrfs",
-                "/mnt",
-                vec![
-                    String::from("subvolume"),
-                    String::from("create"),
-                    String::from("@"),
-                ],
-            ),
-            "Create btrfs subvolume @",
-        );
-        exec_eval(
-            exec_workdir(
-                "btrfs",
-                "/mnt",
-                vec![
-                    String::from("subvolume"),
-                    String::from("create"),
-                    String::from("@home"),
-                ],
-            ),
-            "Create btrfs subvolume @home",
-        );
-        umount("/mnt");
-        mount(format!("{}p3", device).as_str(), "/mnt/", "subvol=@");
-        files_eval(files::create_directory("/mnt/boot"), "create /mnt/boot");
-        files_eval(files::create_directory("/mnt/home"), "create /mnt/home");
-        mount(
-            format!("{}p3", device).as_str(),
-            "/mnt/home",
-            "subvol=@home",
-        );
-        files_eval(files::create_directory("/mnt/efi"), "create /mnt/efi");
-        mount(format!("{}p1", device).as_str(), "/mnt/efi", "");
-    } else if !efi && unakite {
-        exec_eval(
-            exec("mkfs.ext4", vec![format!("{}p1", device)]),
-            format!("format {}p1 as ext4", device).as_str(),
-        );
-        exec_eval(
-            exec(
-                "mkfs.btrfs",
-                vec!["-f".to_string(), format!("{}p2", device)],
-            ),
-            format!("format {}p2 as btrfs", device).as_str(),
-        );
-        mount(format!("{}p2", device).as_str(), "/mnt/", "");
-        exec_eval(
-            exec_workdir(
-                "btrfs",
-                "/mnt",
-                vec![
-                    String::from("subvolume"),
-                    String::from("create"),
-                    String::from("@"),
-                ],
-            ),
-            "Create btrfs subvolume @",
-        );
-        exec_eval(
-            exec_workdir(
-                "btrfs",
-                "/mnt",
-                vec![
-                    String::from("subvolume"),
-                    String::from("create"),
-                    String::from("@home"),
-                ],
-            ),
-            "Create btrfs subvolume @home",
-        );
-        umount("/mnt");
-        mount(format!("{}p2", device).as_str(), "/mnt/", "subvol=@");
-        files_eval(files::create_directory("/mnt/boot"), "create /mnt/boot");
-        files_eval(files::create_directory("/mnt/home"), "create /mnt/home");
-        mount(
-            format!("{}p2", device).as_str(),
-            "/mnt/home",
-            "subvol=@home",
+/// Formats all partitions of `layout` and mounts them below /mnt, root first.
+/// `blockdevices` has the partitions of every disk, more than one disk means a RAID.
+/// `disk_blockdevices` has the partitions of each of the other disks of the layout.
+fn format_mount_layout(
+    layout: &Layout,
+    blockdevices: &[Vec<String>],
+    disk_blockdevices: &[Vec<String>],
+) {
+    if layout
+        .encryption
+        .as_ref()
+        .is_some_and(|e| e.tpm2.is_some() || e.fido2)
+        && layout.needs_grub_cryptodisk()
+    {
+        log::warn!(
+            "/boot is on the encrypted root, GRUB will still ask for the passphrase before the TPM2 or FIDO2 unlocking kicks in"
         );
-        mount(format!("{}p1", device).as_str(), "/mnt/boot", "");
     }
+    // (mountpoint, filesystem, block device) of everything that gets mounted
+    let mut mounts: Vec<(&str, &str, String)> = Vec::new();
+    let mut swaps: Vec<String> = Vec::new();
+    for (i, partition) in layout.partitions.iter().enumerate() {
+        let members: Vec<String> = blockdevices.iter().map(|disk| disk[i].clone()).collect();
+        let mut blockdevice = members[0].clone();
+        if let Some(raid) = &layout.raid {
+            if layout.is_raid_copy(partition) {
+                for copy in &members[1..] {
+                    format(&partition.filesystem, copy, &partition.mkfs_options);
+                    if partition.filesystem == "swap" {
+                        swapon(copy);
+                        swaps.push(copy.clone());
+                    }
+                }
+                if partition.filesystem == "vfat" && raid.level.redundant() {
+                    let copies = members[1..].to_vec();
+                    state::update(|state| state.mirror_esps = copies);
+                }
+            } else if raid.backend == RaidBackend::Btrfs {
+                raid::mkfs_btrfs(raid.level, &members, &partition.mkfs_options);
+                if let Some(mountpoint) = &partition.mountpoint {
+                    mounts.push((mountpoint, &partition.filesystem, blockdevice));
+                }
+                continue;
+            } else {
+                blockdevice =
+                    raid::create_md_array(&format!("jade{}", i + 1), raid.level, &members);
+            }
+        }
+        if let (Some(name), Some(encryption)) = (layout.luks_name(partition), &layout.encryption) {
+            blockdevice = encryption::setup_luks(
+                &blockdevice,
+                name,
+                encryption,
+                layout.holds_root(partition),
+                layout.needs_grub_cryptodisk(),
+            );
+        }
+        if partition.label.as_deref() == Some(UNAKITE_LABEL) {
+            let unakite_root = blockdevice.clone();
+            state::update(|state| state.unakite_root = Some(unakite_root));
+        }
+        match (&layout.lvm, partition.filesystem.as_str()) {
+            (Some(lvm), LVM_FILESYSTEM) => {
+                for (volume, device) in lvm::setup_lvm(&blockdevice, lvm) {
+                    format(&volume.filesystem, &device, &volume.mkfs_options);
+                    if let Some(mountpoint) = &volume.mountpoint {
+                        mounts.push((mountpoint, &volume.filesystem, device));
+                    } else if volume.filesystem == "swap" {
+                        swapon(&device);
+                        swaps.push(device);
+                    }
+                }
+            }
+            _ => {
+                format(&partition.filesystem, &blockdevice, &partition.mkfs_options);
+                if let Some(mountpoint) = &partition.mountpoint {
+                    mounts.push((mountpoint, &partition.filesystem, blockdevice));
+                } else if partition.filesystem == "swap" {
+                    swapon(&blockdevice);
+                    swaps.push(blockdevice);
+                }
+            }
+        }
+    }
+    for (disk, blockdevices) in layout.disks.iter().zip(disk_blockdevices) {
+        for (partition, blockdevice) in disk.partitions.iter().zip(blockdevices) {
+            let mut blockdevice = blockdevice.clone();
+            if let (Some(name), Some(encryption)) =
+                (layout.luks_name(partition), &layout.encryption)
+            {
+                // GRUB only ever reads the install device
+                blockdevice = encryption::setup_luks(&blockdevice, name, encryption, false, false);
+            }
+            format(&partition.filesystem, &blockdevice, &partition.mkfs_options);
+            if let Some(mountpoint) = &partition.mountpoint {
+                mounts.push((mountpoint, &partition.filesystem, blockdevice));
+            } else if partition.filesystem == "swap" {
+                swapon(&blockdevice);
+                swaps.push(blockdevice);
+            }
+        }
+    }
+    if let Some((_, _, root)) = mounts.iter().find(|(mountpoint, _, _)| *mountpoint == "/") {
+        let root = root.clone();
+        state::update(|state| state.root_device = Some(root));
+    }
+    // (mountpoint, block device, mount options), a btrfs root is mounted as its subvolumes
+    let mut targets: Vec<(String, String, String)> = Vec::new();
+    for (mountpoint, filesystem, blockdevice) in mounts {
+        if mountpoint == "/" && filesystem == "btrfs" {
+            targets.extend(create_btrfs_subvolumes(
+                &blockdevice,
+                layout.subvolumes(),
+                &layout.btrfs.mount_options,
+            ));
+        } else {
+            targets.push((mountpoint.to_string(), blockdevice, String::new()));
+        }
+    }
+    mount_targets(targets);
+    swap::setup_swap(layout, &swaps);
 }
 
-fn part_disk(device: &Path, efi: bool, unakite: bool) {
-    let device = device.to_string_lossy().to_string();
-    if efi && !unakite {
-        exec_eval(
-            exec(
-                "mkfs.vfat",
-                vec![String::from("-F32"), format!("{}1", device)],
-            ),
-            format!("format {}1 as fat32", device).as_str(),
-        );
-        exec_eval(
-            exec("mkfs.btrfs", vec!["-f".to_string(), format!("{}2", device)]),
-            format!("format {}2 as btrfs", device).as_str(),
-        );
-        mount(format!("{}2", device).as_str(), "/mnt", "");
-        exec_eval(
-            exec_workdir(
-                "btrfs",
-                "/mnt",
-                vec![
-                    String::from("subvolume"),
-                    String::from("create"),
-                    String::from("@"),
-                ],
-            ),
-            "Create btrfs subvolume @",
-        );
-        exec_eval(
-            exec_workdir(
-                "btrfs",
-                "/mnt",
-                vec![
-                    String::from("subvolume"),
-                    String::from("create"),
-                    String::from("@home"),
-                ],
-            ),
-            "Create btrfs subvolume @home",
-        );
-        umount("/mnt");
-        mount(format!("{}2", device).as_str(), "/mnt/", "subvol=@");
-        files_eval(files::create_directory("/mnt/boot"), "create /mnt/boot");
-        files_eval(files::create_directory("/mnt/home"), "create /mnt/home");
-        mount(format!("{}2", device).as_str(), "/mnt/home", "subvol=@home");
-        files_eval(files::create_directory("/mnt/efi"), "create /mnt/efi");
-        mount(format!("{}1", device).as_str(), "/mnt/efi", "");
-    } else if !efi && !unakite {
-        exec_eval(
-            exec("mkfs.ext4", vec![format!("{}1", device)]),
-            format!("format {}1 as ext4", device).as_str(),
-        );
-        exec_eval(
-            exec("mkfs.btrfs", vec!["-f".to_string(), format!("{}2", device)]),
-            format!("format {}2 as btrfs", device).as_str(),
-        );
-        mount(format!("{}2", device).as_str(), "/mnt/", "");
-        exec_eval(
-            exec_workdir(
-                "btrfs",
-                "/mnt",
-                vec![
-                    String::from("subvolume"),
-                    String::from("create"),
-                    String::from("@"),
-                ],
-            ),
-            "Create btrfs subvolume @",
-        );
-        exec_eval(
-            exec_workdir(
-                "btrfs",
-                "/mnt",
-                vec![
-                    String::from("subvolume"),
-                    String::from("create"),
-                    String::from("@home"),
-                ],
-            ),
-            "create btrfs subvolume @home",
-        );
-        umount("/mnt");
-        mount(format!("{}2", device).as_str(), "/mnt/", "subvol=@");
-        files_eval(
-            files::create_directory("/mnt/boot"),
-            "create directory /mnt/boot",
-        );
+/// Mounts `(mountpoint, block device, mount options)` below /mnt, shortest mountpoint first
+fn mount_targets(mut targets: Vec<(String, String, String)>) {
+    targets.sort_by_key(|(mountpoint, _, _)| mountpoint.len());
+    for (mountpoint, blockdevice, options) in targets {
+        let target = format!("/mnt{}", mountpoint.trim_end_matches('/'));
         files_eval(
-            files::create_directory("/mnt/home"),
-            "create directory /mnt/home",
-        );
-        mount(format!("{}2", device).as_str(), "/mnt/home", "subvol=@home");
-        mount(format!("{}1", device).as_str(), "/mnt/boot", "");
-    } else if efi && unakite {
-        exec_eval(
-            exec(
-                "mkfs.vfat",
-                vec![String::from("-F32"), format!("{}1", device)],
-            ),
-            format!("format {}1 as fat32", device).as_str(),
-        );
-        exec_eval(
-            exec("mkfs.btrfs", vec!["-f".to_string(), format!("{}2", device)]),
-            format!("format {}2 as btrfs", device).as_str(),
-        );
-        exec_eval(
-            exec("mkfs.btrfs", vec!["-f".to_string(), format!("{}3", device)]),
-            format!("format {}3 as btrfs", device).as_str(),
-        );
-        mount(format!("{}3", device).as_str(), "/mnt", "");
-        exec_eval(
-            exec_workdir(
-                "btrfs",
-                "/mnt",
-                vec![
-                    String::from("subvolume"),
-                    String::from("create"),
-                    String::from("@"),
-                ],
-            ),
-            "Create btrfs subvolume @",
-        );
-        exec_eval(
-            exec_workdir(
-                "btrfs",
-                "/mnt",
-                vec![
-                    String::from("subvolume"),
-                    String::from("create"),
-                    String::from("@home"),
-                ],
-            ),
-            "Create btrfs subvolume @home",
-        );
-        umount("/mnt");
-        mount(format!("{}3", device).as_str(), "/mnt/", "subvol=@");
-        files_eval(files::create_directory("/mnt/boot"), "create /mnt/boot");
-        files_eval(files::create_directory("/mnt/home"), "create /mnt/home");
-        mount(format!("{}3", device).as_str(), "/mnt/home", "subvol=@home");
-        files_eval(files::create_directory("/mnt/efi"), "create /mnt/efi");
-        mount(format!("{}1", device).as_str(), "/mnt/efi", "");
-    } else if !efi && unakite {
-        exec_eval(
-            exec("mkfs.ext4", vec![format!("{}1", device)]),
-            format!("format {}1 as ext4", device).as_str(),
-        );
-        exec_eval(
-            exec("mkfs.btrfs", vec!["-f".to_string(), format!("{}2", device)]),
-            format!("format {}2 as btrfs", device).as_str(),
-        );
-        exec_eval(
-            exec("mkfs.btrfs", vec!["-f".to_string(), format!("{}3", device)]),
-            format!("format {}3 as btrfs", device).as_str(),
-        );
-        mount(format!("{}3", device).as_str(), "/mnt/", "");
-        exec_eval(
-            exec_workdir(
-                "btrfs",
-                "/mnt",
-                vec![
-                    String::from("subvolume"),
-                    String::from("create"),
-                    String::from("@"),
-                ],
-            ),
-            "Create btrfs subvolume @",
-        );
+            files::create_directory(&target),
+            format!("create {target}").as_str(),
+        );
+        mount(&blockdevice, &target, &options);
+        let options = options
+            .split(',')
+            .filter(|o| !o.is_empty())
+            .map(String::from)
+            .collect();
+        state::update(|state| {
+            state.add_mount(state::Mount {
+                device: blockdevice,
+                mountpoint,
+                options,
+            })
+        });
+    }
+    files_eval(files::create_directory("/mnt/boot"), "create /mnt/boot");
+}
+
+/// Creates `subvolumes` on a btrfs filesystem unless they already exist,
+/// returns where each of them gets mounted with which options
+fn create_btrfs_subvolumes(
+    blockdevice: &str,
+    subvolumes: Vec<Subvolume>,
+    mount_options: &[String],
+) -> Vec<(String, String, String)> {
+    mount(blockdevice, "/mnt", "");
+    for subvolume in &subvolumes {
+        if Path::new("/mnt").join(&subvolume.name).exists() {
+            log::info!("Reusing existing btrfs subvolume {}", subvolume.name);
+            continue;
+        }
         exec_eval(
             exec_workdir(
                 "btrfs",
@@ -667,24 +727,26 @@ fn part_disk(device: &Path, efi: bool, unakite: bool) {
                 vec![
                     String::from("subvolume"),
                     String::from("create"),
-                    String::from("@home"),
+                    subvolume.name.clone(),
                 ],
             ),
-            "create btrfs subvolume @home",
-        );
-        umount("/mnt");
-        mount(format!("{}3", device).as_str(), "/mnt/", "subvol=@");
-        files_eval(
-            files::create_directory("/mnt/boot"),
-            "create directory /mnt/boot",
-        );
-        files_eval(
-            files::create_directory("/mnt/home"),
-            "create directory /mnt/home",
+            format!("Create btrfs subvolume {}", subvolume.name).as_str(),
         );
-        mount(format!("{}3", device).as_str(), "/mnt/home", "subvol=@home");
-        mount(format!("{}1", device).as_str(), "/mnt/boot", "");
     }
+    umount("/mnt");
+    subvolumes
+        .into_iter()
+        .map(|subvolume| {
+            let mut options = vec![format!("subvol={}", subvolume.name)];
+            options.extend(mount_options.iter().cloned());
+            options.extend(subvolume.mount_options);
+            (
+                subvolume.mountpoint,
+                String::from(blockdevice),
+                options.join(","),
+            )
+        })
+        .collect()
 }
 
 pub fn mount(partition: &str, mountpoint: &str, options: &str) {
@@ -716,6 +778,15 @@ pub fn mount(partition: &str, mountpoint: &str, options: &str) {
     }
 }
 
+/// Activates swap and records it for fstab
+fn swapon(blockdevice: &str) {
+    exec_eval(
+        exec("swapon", vec![String::from(blockdevice)]),
+        format!("Activate swap on {blockdevice}").as_str(),
+    );
+    state::update(|state| state.add_swap(blockdevice));
+}
+
 pub fn umount(mountpoint: &str) {
     exec_eval(
         exec("umount", vec![String::from(mountpoint)]),
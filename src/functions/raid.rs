@@ -0,0 +1,108 @@
+use crate::args::RaidLevel;
+use crate::internal::exec::*;
+use crate::internal::state;
+use crate::internal::*;
+
+/// Where the copies of the EFI system partition get mounted to update them
+const ESP_MIRROR_MOUNTPOINT: &str = "/tmp/jade-esp";
+
+/// Creates an mdadm array called `name` out of `members`, returns its block device
+pub fn create_md_array(name: &str, level: RaidLevel, members: &[String]) -> String {
+    let array = format!("/dev/md/{name}");
+    let mut args = vec![
+        String::from("--create"),
+        array.clone(),
+        String::from("--run"),
+        String::from("--metadata=1.2"),
+        format!("--level={}", level.name()),
+        format!("--raid-devices={}", members.len()),
+    ];
+    args.extend(members.iter().cloned());
+    exec_eval(
+        exec("mdadm", args),
+        format!(
+            "Create {} array {array} from {}",
+            level.name(),
+            members.join(", ")
+        )
+        .as_str(),
+    );
+    state::update(|state| {
+        state.add_initramfs_hook("mdadm_udev");
+        state.add_package("mdadm");
+    });
+    array
+}
+
+/// Formats `members` as one btrfs filesystem using the `level` profile for data.
+/// Metadata of a raid0 filesystem is still mirrored, losing it would lose everything.
+pub fn mkfs_btrfs(level: RaidLevel, members: &[String], options: &[String]) {
+    let metadata = match level {
+        RaidLevel::Raid0 => RaidLevel::Raid1,
+        level => level,
+    };
+    let mut args = vec![
+        String::from("-f"),
+        format!("--data={}", level.name()),
+        format!("--metadata={}", metadata.name()),
+    ];
+    args.extend(options.iter().cloned());
+    args.extend(members.iter().cloned());
+    exec_eval(
+        exec("mkfs.btrfs", args),
+        format!("Create {} btrfs on {}", level.name(), members.join(", ")).as_str(),
+    );
+}
+
+/// Records the mdadm arrays in mdadm.conf, so the initramfs assembles them under the same names
+pub fn write_mdadm_conf() {
+    let arrays = match exec_output(
+        "mdadm",
+        vec![String::from("--detail"), String::from("--scan")],
+    ) {
+        Ok(arrays) => arrays,
+        Err(e) => crash(format!("List mdadm arrays  ERROR: {e}"), 1),
+    };
+    files_eval(
+        files::append_file("/mnt/etc/mdadm.conf", &arrays),
+        "Add RAID arrays to mdadm.conf",
+    );
+}
+
+/// Copies the bootloader from the EFI system partition at `esp` to the copies of it on the
+/// other disks of the RAID, so the system still boots when the first disk fails
+pub fn mirror_esp(esp: &str, mirrors: &[String]) {
+    for mirror in mirrors {
+        files_eval(
+            files::create_directory(ESP_MIRROR_MOUNTPOINT),
+            format!("create {ESP_MIRROR_MOUNTPOINT}").as_str(),
+        );
+        exec_eval(
+            exec(
+                "mount",
+                vec![String::from(mirror), String::from(ESP_MIRROR_MOUNTPOINT)],
+            ),
+            format!("mount {mirror} at {ESP_MIRROR_MOUNTPOINT}").as_str(),
+        );
+        exec_eval(
+            exec(
+                "cp",
+                vec![
+                    String::from("-rT"),
+                    format!("{esp}/EFI"),
+                    format!("{ESP_MIRROR_MOUNTPOINT}/EFI"),
+                ],
+            ),
+            format!("Copy the bootloader to {mirror}").as_str(),
+        );
+        exec_eval(
+            exec("umount", vec![String::from(ESP_MIRROR_MOUNTPOINT)]),
+            format!("unmount {ESP_MIRROR_MOUNTPOINT}").as_str(),
+        );
+    }
+    if !mirrors.is_empty() {
+        log::warn!(
+            "The EFI system partitions on the other RAID disks are copies, update them after bootloader updates"
+        );
+    }
+}
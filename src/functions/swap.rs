@@ -0,0 +1,127 @@
+use crate::functions::encryption;
+use crate::functions::layout::{Layout, Size, Swap};
+use crate::internal::exec::*;
+use crate::internal::state;
+use crate::internal::*;
+
+/// Returns the physical offset of the first extent of a file from `filefrag -v` output,
+/// in filesystem blocks which is what the kernel expects as `resume_offset`
+fn parse_filefrag_offset(output: &str) -> Option<u64> {
+    let extent = output
+        .lines()
+        .map(str::trim)
+        .find(|line| line.starts_with("0:"))?;
+    let physical = extent.split(':').nth(2)?;
+    physical.split("..").next()?.trim().parse().ok()
+}
+
+/// Creates and activates the swapfile of the layout below /mnt,
+/// returns the offset of it on the root filesystem
+fn create_swapfile(size_mib: u64, btrfs: bool) -> u64 {
+    let path = format!("/mnt{}", Swap::file_path(btrfs));
+    let (command, args) = if btrfs {
+        // makes the file nocow and uncompressed, which btrfs needs for swapfiles
+        (
+            "btrfs",
+            vec![
+                String::from("filesystem"),
+                String::from("mkswapfile"),
+                String::from("--size"),
+                format!("{size_mib}m"),
+                path.clone(),
+            ],
+        )
+    } else {
+        (
+            "mkswap",
+            vec![
+                String::from("--size"),
+                format!("{size_mib}M"),
+                String::from("--file"),
+                path.clone(),
+            ],
+        )
+    };
+    exec_eval(
+        exec(command, args),
+        format!("Create {size_mib}MiB swapfile {path}").as_str(),
+    );
+    exec_eval(
+        exec("swapon", vec![path.clone()]),
+        format!("Activate swapfile {path}").as_str(),
+    );
+    state::update(|state| state.add_swap(Swap::file_path(btrfs)));
+    let offset = if btrfs {
+        exec_output(
+            "btrfs",
+            vec![
+                String::from("inspect-internal"),
+                String::from("map-swapfile"),
+                String::from("-r"),
+                path.clone(),
+            ],
+        )
+        .ok()
+        .and_then(|offset| offset.parse().ok())
+    } else {
+        exec_output("filefrag", vec![String::from("-v"), path.clone()])
+            .ok()
+            .and_then(|output| parse_filefrag_offset(&output))
+    };
+    match offset {
+        Some(offset) => offset,
+        None => crash(format!("Find the offset of swapfile {path}"), 1),
+    }
+}
+
+/// Sets up the swap of `layout` once everything is mounted. `swap_devices` are the swap
+/// partitions or logical volumes that were already activated.
+pub fn setup_swap(layout: &Layout, swap_devices: &[String]) {
+    let Some(swap) = &layout.swap else {
+        return;
+    };
+    let (device, offset) = if swap.file {
+        let Size::Mib(size_mib) = swap.size else {
+            crash("A swapfile needs an absolute size", 1);
+        };
+        let btrfs = layout.root_filesystem() == Some("btrfs");
+        let offset = create_swapfile(size_mib, btrfs);
+        let root = match state::load().root_device {
+            Some(root) => root,
+            None => crash("Find the root filesystem for the swapfile", 1),
+        };
+        (root, Some(offset))
+    } else {
+        match swap_devices.first() {
+            Some(device) => (device.clone(), None),
+            None => crash("Find the swap partition", 1),
+        }
+    };
+    if !swap.hibernate {
+        return;
+    }
+    let resume = format!("UUID={}", encryption::uuid(&device));
+    log::info!("Hibernation resumes from {resume}");
+    state::update(|state| {
+        state.resume = Some(resume);
+        state.resume_offset = offset;
+        state.add_initramfs_hook("resume");
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_filefrag_offset() {
+        let output = "Filesystem type is: ef53
+File size of /mnt/swapfile is 1073741824 (262144 blocks of 4096 bytes)
+ ext:     logical_offset:        physical_offset: length:   expected: flags:
+   0:        0..   32767:      34816..     67583:  32768:
+   1:    32768..   63487:      67584..     98303:  30720:
+/mnt/swapfile: 2 extents found";
+        assert_eq!(parse_filefrag_offset(output), Some(34816));
+        assert_eq!(parse_filefrag_offset("no extents"), None);
+    }
+}
@@ -1,7 +1,9 @@
-use crate::args::DesktopSetup;
+use crate::args::{DesktopSetup, FstabReference, Kernel, Shell};
 use crate::functions::partition::mount;
 use crate::functions::*;
+use crate::internal::config::INSTALL_RECORD_PATH;
 use crate::internal::exec::*;
+use crate::internal::state::Mount;
 use crate::internal::*;
 use std::path::PathBuf;
 pub fn install_bootloader_efi(efidir: PathBuf) {
@@ -115,9 +117,29 @@ pub fn remount(root: &str, oldroot: &str, efi: bool, efidir: &str, bootdev: &str
 
 pub fn setup_unakite(root: &str, oldroot: &str, efi: bool, efidir: &str, bootdev: &str) {
     log::debug!("Setting up Unakite");
+    // carry over the install config of the crystal installation, if there is one
+    let install_record = std::fs::read_to_string(INSTALL_RECORD_PATH).ok();
     remount(root, oldroot, efi, efidir, bootdev, true);
-    base::install_base_packages("linux".to_string());
-    base::genfstab();
+    base::install_base_packages(Kernel::Linux);
+    // the crystal install is mounted elsewhere now, so its fstab entries don't apply
+    let boot = if efi {
+        efidir.trim_start_matches("/mnt")
+    } else {
+        "/boot"
+    };
+    let mounts = [
+        Mount {
+            device: String::from(root),
+            mountpoint: String::from("/"),
+            options: Vec::new(),
+        },
+        Mount {
+            device: String::from(bootdev),
+            mountpoint: String::from(boot),
+            options: Vec::new(),
+        },
+    ];
+    fstab::write(&mounts, &[], FstabReference::Uuid);
     locale::set_locale("en_US.UTF-8 UTF-8".to_string());
     locale::set_timezone("Europe/Berlin"); // TODO: get the proper timezone
     network::set_hostname("unakite");
@@ -127,7 +149,7 @@ pub fn setup_unakite(root: &str, oldroot: &str, efi: bool, efidir: &str, bootdev
         true,
         "Cp7oN04ZY0PsA", // unakite
         false,
-        "/bin/bash",
+        Shell::Bash,
     );
     exec_eval(
         exec(
@@ -159,16 +181,16 @@ pub fn setup_unakite(root: &str, oldroot: &str, efi: bool, efidir: &str, bootdev
     users::root_pass("Cp7oN04ZY0PsA"); // unakite
     desktops::install_desktop_setup(DesktopSetup::Xfce);
     install(vec!["gparted", "firefox"]);
-    exec_eval(
-        exec(
-            "cp",
-            vec![
-                String::from("/tmp/jade.json"),
-                String::from("/mnt/etc/installSettings.json"),
-            ],
-        ),
-        "Copy jade.json to /etc/installSettings.json in unakite",
-    );
+    if let Some(record) = &install_record {
+        files_eval(
+            files::create_directory("/mnt/etc/jade"),
+            "create /mnt/etc/jade in unakite",
+        );
+        files_eval(
+            files::write_file(INSTALL_RECORD_PATH, record),
+            "Copy install config to /etc/jade/install.json in unakite",
+        );
+    }
     remount(root, oldroot, efi, efidir, bootdev, false);
     exec_eval(
         exec_chroot(
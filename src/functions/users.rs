@@ -1,9 +1,9 @@
+use crate::args::Shell;
 use crate::internal::exec::*;
 use crate::internal::*;
 use std::process::Command;
 
-pub fn new_user(username: &str, hasroot: bool, password: &str, do_hash_pass: bool, shell: &str) {
-    let shell: &str = shell;
+pub fn new_user(username: &str, hasroot: bool, password: &str, do_hash_pass: bool, shell: Shell) {
     if do_hash_pass {
         let hashed_pass = &*hash_pass(password).stdout;
         let _password = match std::str::from_utf8(hashed_pass) {
@@ -12,21 +12,19 @@ pub fn new_user(username: &str, hasroot: bool, password: &str, do_hash_pass: boo
         };
     }
     let shell_to_install = match shell {
-        "bash" => "bash",
-        "csh" => "tcsh",
-        "fish" => "fish",
-        "tcsh" => "tcsh",
-        "zsh" => "zsh",
-        &_ => "bash",
+        Shell::Bash => "bash",
+        Shell::Csh => "tcsh",
+        Shell::Fish => "fish",
+        Shell::Tcsh => "tcsh",
+        Shell::Zsh => "zsh",
     };
     install::install(vec![shell_to_install]);
     let shell_path = match shell {
-        "bash" => "/bin/bash",
-        "csh" => "/usr/bin/csh",
-        "fish" => "/usr/bin/fish",
-        "tcsh" => "/usr/bin/tcsh",
-        "zsh" => "/usr/bin/zsh",
-        &_ => "/usr/bin/bash",
+        Shell::Bash => "/bin/bash",
+        Shell::Csh => "/usr/bin/csh",
+        Shell::Fish => "/usr/bin/fish",
+        Shell::Tcsh => "/usr/bin/tcsh",
+        Shell::Zsh => "/usr/bin/zsh",
     };
     exec_eval(
         exec_chroot(
@@ -0,0 +1,86 @@
+use crate::functions::partition::{mount, umount};
+use crate::internal::config::{install_config, load_config, parse_config};
+use crate::internal::*;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::Duration;
+
+/// Filesystem label of install media that jade looks for a config on
+pub const DEFAULT_CONFIG_LABEL: &str = "JADE";
+/// Name of the config file on labeled install media
+const CONFIG_FILE_NAME: &str = "jade.json";
+/// Where labeled install media gets mounted while reading the config
+const MEDIA_MOUNTPOINT: &str = "/run/jade/media";
+
+/// Returns the value of a `key=value` parameter on a kernel command line
+pub fn cmdline_param(cmdline: &str, key: &str) -> Option<String> {
+    cmdline
+        .split_whitespace()
+        .filter_map(|param| param.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v.trim_matches('"').to_string())
+}
+
+/// Finds, validates and installs a config without any user interaction
+pub fn auto_install(label: &str, timeout: Option<u64>) {
+    let cmdline = std::fs::read_to_string("/proc/cmdline").unwrap_or_default();
+    let config = match cmdline_param(&cmdline, "jade.config") {
+        Some(path) => {
+            log::info!("Using config {path} from kernel command line");
+            load_config(Path::new(&path))
+        }
+        None => {
+            let media = PathBuf::from("/dev/disk/by-label").join(label);
+            if !media.exists() {
+                crash(
+                    format!("No jade.config= on the kernel command line and no media labeled {label} found"),
+                    1,
+                );
+            }
+            log::info!("Using config from media labeled {label}");
+            files_eval(
+                files::create_directory(MEDIA_MOUNTPOINT),
+                format!("create {MEDIA_MOUNTPOINT}").as_str(),
+            );
+            mount(&media.to_string_lossy(), MEDIA_MOUNTPOINT, "ro");
+            let configpath = Path::new(MEDIA_MOUNTPOINT).join(CONFIG_FILE_NAME);
+            // unmounted before anything can crash, so the media isn't left mounted
+            let data = std::fs::read_to_string(&configpath);
+            umount(MEDIA_MOUNTPOINT);
+            match data {
+                Ok(data) => parse_config(&data, &configpath),
+                Err(e) => crash(format!("Read config file {configpath:?}  ERROR: {e}"), 1),
+            }
+        }
+    };
+
+    let timeout = timeout
+        .or_else(|| cmdline_param(&cmdline, "jade.timeout").and_then(|t| t.parse().ok()))
+        .unwrap_or(0);
+    for remaining in (1..=timeout).rev() {
+        log::warn!("Starting unattended installation in {remaining}s, press Ctrl+C to abort");
+        sleep(Duration::from_secs(1));
+    }
+    // the timeout above is the only confirmation an unattended install gets
+    install_config(config, true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_cmdline_params() {
+        let cmdline = "BOOT_IMAGE=/vmlinuz quiet jade.config=/run/jade.json jade.timeout=\"30\"\n";
+        assert_eq!(
+            cmdline_param(cmdline, "jade.config"),
+            Some(String::from("/run/jade.json"))
+        );
+        assert_eq!(
+            cmdline_param(cmdline, "jade.timeout"),
+            Some(String::from("30"))
+        );
+        assert_eq!(cmdline_param(cmdline, "quiet"), None);
+        assert_eq!(cmdline_param(cmdline, "jade"), None);
+    }
+}
@@ -1,25 +1,49 @@
 use crate::args;
-use crate::args::{DesktopSetup, PartitionMode};
+use crate::args::{
+    BootloaderType, DesktopSetup, FstabReference, Kernel, PartitionMode, RootFilesystem, Shell,
+    WipeMethod,
+};
+use crate::functions::layout::{
+    Btrfs, DiskLayout, Encryption, ExistingEsp, Layout, LayoutPartition, Lvm, Raid, Shrink, Swap,
+};
 use crate::functions::*;
+use crate::internal::exec::*;
+use crate::internal::secret::Secret;
 use crate::internal::*;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Serialize, Deserialize)]
-struct Config {
+pub struct Config {
     partition: Partition,
     bootloader: Bootloader,
     locale: Locale,
     networking: Networking,
     users: Vec<Users>,
-    rootpass: String,
-    desktop: String,
+    rootpass: Secret,
+    desktop: DesktopSetup,
     timeshift: bool,
     flatpak: bool,
     zramd: bool,
     extra_packages: Vec<String>,
     unakite: Unakite,
-    kernel: String,
+    #[serde(default)]
+    kernel: Kernel,
+    /// how fstab refers to the filesystems: uuid, partuuid or label
+    #[serde(default)]
+    fstab_reference: FstabReference,
+    /// what to do once the installation is finished
+    #[serde(default)]
+    on_finish: FinishAction,
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum FinishAction {
+    #[default]
+    None,
+    Reboot,
+    Poweroff,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -27,13 +51,67 @@ struct Partition {
     device: String,
     mode: PartitionMode,
     efi: bool,
-    partitions: Vec<String>,
+    partitions: Vec<PartitionSpec>,
+    /// custom layout for automatic partitioning, defaults to the boot/efi, (unakite,) root layout
+    #[serde(default)]
+    layout: Option<Vec<LayoutPartition>>,
+    /// encrypt the root (and optionally /home) with LUKS2 when partitioning automatically
+    #[serde(default)]
+    encryption: Option<Encryption>,
+    /// put the root (or the lvm partition of a custom layout) into an LVM volume group
+    #[serde(default)]
+    lvm: Option<Lvm>,
+    /// build a software RAID across `device` and the disks listed here
+    #[serde(default)]
+    raid: Option<Raid>,
+    /// the filesystem of the root when partitioning automatically without a custom layout
+    #[serde(default)]
+    root_filesystem: RootFilesystem,
+    /// subvolumes and mount options of a btrfs root
+    #[serde(default)]
+    btrfs: Btrfs,
+    /// a swap partition or swapfile, optionally used for hibernation
+    #[serde(default)]
+    swap: Option<Swap>,
+    /// shrink this partition of another OS to make room when installing alongside it
+    #[serde(default)]
+    shrink: Option<Shrink>,
+    /// wipe old signatures off the disks before partitioning them automatically
+    #[serde(default)]
+    wipe: Option<WipeMethod>,
+    /// other disks to partition automatically along with `device`, e.g. for /home on a hard drive
+    #[serde(default)]
+    disks: Vec<DiskLayout>,
+    /// mount this existing EFI system partition instead of creating one, or `auto` to find it
+    #[serde(default)]
+    esp: Option<ExistingEsp>,
+    /// install into the image file at `device` instead of a disk, created if it doesn't exist
+    #[serde(default)]
+    image: Option<image::Image>,
+}
+
+/// A manual partition, either the short `mountpoint:blockdevice:filesystem[:options]` form
+/// the command line uses or the structured form
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum PartitionSpec {
+    Short(String),
+    Full(args::Partition),
+}
+
+impl PartitionSpec {
+    fn to_partition(&self) -> Result<args::Partition, String> {
+        match self {
+            PartitionSpec::Short(spec) => spec.parse(),
+            PartitionSpec::Full(partition) => Ok(partition.clone().strip_mnt()),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 struct Bootloader {
     /// which bootloader to be installed
-    r#type: String,
+    r#type: BootloaderType,
     /// esp mount location
     location: String,
     /// esp block device
@@ -58,9 +136,9 @@ struct Networking {
 #[derive(Serialize, Deserialize)]
 struct Users {
     name: String,
-    password: String,
+    password: Secret,
     hasroot: bool,
-    shell: String,
+    shell: Shell,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -72,8 +150,50 @@ struct Unakite {
     bootdev: String,
 }
 
-pub fn read_config(configpath: PathBuf) {
-    let data = std::fs::read_to_string(&configpath);
+/// What gets saved to `/etc/jade/install.json` in the installed system
+#[derive(Serialize)]
+struct InstallRecord<'a> {
+    jade_version: &'a str,
+    config: &'a Config,
+    /// The layout automatic partitioning resolved the config to, the config alone doesn't
+    /// say which partitions, volumes and swap the defaults made
+    layout: Option<Layout>,
+}
+
+pub const INSTALL_RECORD_PATH: &str = "/mnt/etc/jade/install.json";
+
+fn install_record(config: &Config) -> InstallRecord<'_> {
+    InstallRecord {
+        jade_version: env!("CARGO_PKG_VERSION"),
+        config,
+        layout: (config.partition.mode != PartitionMode::Manual).then(|| auto_layout(config)),
+    }
+}
+
+/// Saves the resolved config (with all secrets redacted) into the installed system
+fn save_install_record(config: &Config) {
+    let json = match serde_json::to_string_pretty(&install_record(config)) {
+        Ok(json) => json,
+        Err(e) => crash(format!("Serialize install record  ERROR: {}", e), 1),
+    };
+    files_eval(
+        files::create_directory("/mnt/etc/jade"),
+        "create /mnt/etc/jade",
+    );
+    files_eval(
+        files::write_file(INSTALL_RECORD_PATH, &json),
+        "Save install config to /etc/jade/install.json",
+    );
+}
+
+pub fn read_config(configpath: PathBuf, yes: bool) {
+    let config = load_config(&configpath);
+    install_config(config, yes);
+}
+
+/// Reads, parses and validates a config file
+pub fn load_config(configpath: &Path) -> Config {
+    let data = std::fs::read_to_string(configpath);
     match &data {
         Ok(_) => {
             log::debug!("[ \x1b[2;1;32mOK\x1b[0m ] Read config file {configpath:?}");
@@ -85,8 +205,12 @@ pub fn read_config(configpath: PathBuf) {
             );
         }
     }
-    let config: std::result::Result<Config, serde_json::Error> =
-        serde_json::from_str(&data.unwrap());
+    parse_config(&data.unwrap(), configpath)
+}
+
+/// Parses and validates the config read from `configpath`
+pub fn parse_config(data: &str, configpath: &Path) -> Config {
+    let config: std::result::Result<Config, serde_json::Error> = serde_json::from_str(data);
     match &config {
         Ok(_) => {
             log::debug!("[ \x1b[2;1;32mOK\x1b[0m ] Parse config file {configpath:?}",);
@@ -96,44 +220,237 @@ pub fn read_config(configpath: PathBuf) {
         }
     }
     let config: Config = config.unwrap();
-    log::info!("Block device to use : /dev/{}", config.partition.device);
+    validate_config(&config);
+    log::debug!("[ \x1b[2;1;32mOK\x1b[0m ] Validate config file {configpath:?}");
+    config
+}
+
+/// The layout to use for automatic partitioning
+fn auto_layout(config: &Config) -> Layout {
+    let mut layout = match &config.partition.layout {
+        Some(partitions) => Layout {
+            partitions: partitions.clone(),
+            encryption: None,
+            lvm: config.partition.lvm.clone(),
+            raid: None,
+            btrfs: Btrfs::default(),
+            swap: None,
+            shrink: None,
+            wipe: None,
+            disks: Vec::new(),
+            esp: None,
+        },
+        None => {
+            let mut layout = Layout::default_for(config.partition.efi, config.unakite.enable);
+            layout.set_root_filesystem(config.partition.root_filesystem.name());
+            if config.partition.lvm.is_some() {
+                layout.use_lvm(config.partition.lvm.clone());
+            }
+            layout
+        }
+    };
+    layout.encryption = config.partition.encryption.clone();
+    layout.btrfs = config.partition.btrfs.clone();
+    layout.shrink = config.partition.shrink.clone();
+    layout.wipe = config.partition.wipe;
+    if let Some(swap) = &config.partition.swap {
+        layout.set_swap(swap.clone());
+    }
+    layout.disks = config.partition.disks.clone();
+    for disk in layout.disks.iter_mut() {
+        disk.device = dev_path(&disk.device);
+    }
+    layout.esp = config.partition.esp.clone().map(|esp| match esp {
+        ExistingEsp::Device(device) => ExistingEsp::Device(dev_path(device)),
+        ExistingEsp::Detect => ExistingEsp::Detect,
+    });
+    layout.raid = config.partition.raid.clone().map(|mut raid| {
+        for device in raid.devices.iter_mut() {
+            *device = dev_path(&device);
+        }
+        raid
+    });
+    layout
+}
+
+/// Devices are given like `device`, without /dev/
+fn dev_path(device: impl AsRef<Path>) -> PathBuf {
+    Path::new("/dev/").join(device)
+}
+
+/// The partitions to use for manual partitioning
+fn manual_partitions(config: &Config) -> Vec<args::Partition> {
+    config
+        .partition
+        .partitions
+        .iter()
+        .map(|partition| match partition.to_partition() {
+            Ok(partition) => partition,
+            Err(e) => crash(format!("Invalid manual partition: {e}"), 1),
+        })
+        .collect()
+}
+
+/// The image file to install into, if `device` is one
+fn image_file(config: &Config) -> Option<PathBuf> {
+    let device = dev_path(&config.partition.device);
+    (config.partition.image.is_some() || image::is_image(&device)).then_some(device)
+}
+
+/// Checks everything that can be checked before touching any disk
+fn validate_config(config: &Config) {
+    if let Err(e) = partition::check_supported(config.partition.root_filesystem.name()) {
+        crash(format!("Invalid root_filesystem: {e}"), 1);
+    }
+    if config.partition.mode == PartitionMode::Manual {
+        if let Err(e) =
+            args::validate_partitions(&manual_partitions(config), config.partition.lvm.as_ref())
+        {
+            crash(format!("Invalid manual partition: {e}"), 1);
+        }
+    }
+    // a relative path is taken as a name below /dev/, which is in RAM
+    if config.partition.image.is_some() {
+        if let Err(e) = image::check_file(Path::new(&config.partition.device)) {
+            crash(e, 1);
+        }
+    }
+    let image_mib = image_file(config).map(|file| {
+        let size = config.partition.image.as_ref().and_then(|image| image.size);
+        match image::size_mib(&file, size) {
+            Ok(mib) => mib,
+            Err(e) => crash(format!("Invalid image: {e}"), 1),
+        }
+    });
+    if config.partition.mode == PartitionMode::Auto {
+        let layout = auto_layout(config);
+        let mut devices = vec![dev_path(&config.partition.device)];
+        if let Some(raid) = &layout.raid {
+            devices.extend(raid.devices.iter().cloned());
+        }
+        // an image is created when it doesn't exist, so only the disks have to be there
+        let disks = if image_mib.is_some() {
+            &devices[1..]
+        } else {
+            &devices[..]
+        };
+        for device in disks {
+            if !device.exists() {
+                crash(format!("The device {device:?} doesn't exist"), 1);
+            }
+        }
+        let disk_mib = disks
+            .iter()
+            .map(|d| disks::disk_size_mib(d))
+            .chain(image_mib)
+            .min();
+        if let Err(e) = layout
+            .validate(config.partition.efi)
+            .and_then(|_| layout.plan(disk_mib.unwrap_or(0)))
+        {
+            crash(format!("Invalid partition layout: {e}"), 1);
+        }
+        for disk in &layout.disks {
+            if !disk.device.exists() {
+                crash(format!("The device {:?} doesn't exist", disk.device), 1);
+            }
+            let disk_mib = disks::disk_size_mib(&disk.device);
+            if let Err(e) = layout.for_disk(disk).plan(disk_mib) {
+                crash(
+                    format!("Invalid partition layout for {:?}: {e}", disk.device),
+                    1,
+                );
+            }
+        }
+    }
+    if config.partition.mode == PartitionMode::Alongside {
+        let device = dev_path(&config.partition.device);
+        if image_mib.is_none() && !device.exists() {
+            crash(format!("The device {device:?} doesn't exist"), 1);
+        }
+        let layout = auto_layout(config);
+        if let Err(e) = dualboot::check_layout(&layout) {
+            crash(e, 1);
+        }
+        // the free space is only known once the other OS' partition has been shrunk
+        if let Err(e) = layout.validate(config.partition.efi) {
+            crash(format!("Invalid partition layout: {e}"), 1);
+        }
+    }
+    if config.unakite.enable
+        && config.partition.layout.is_none()
+        && (config.partition.lvm.is_some() || config.partition.raid.is_some())
+    {
+        crash(
+            "Unakite needs the root on a plain partition, use a custom layout with LVM or RAID",
+            1,
+        );
+    }
+    if config.bootloader.r#type == BootloaderType::Refind && config.bootloader.device.is_none() {
+        crash("efi block device not specified", 1);
+    }
+    if config.bootloader.r#type == BootloaderType::Refind && config.partition.encryption.is_some() {
+        crash("Encryption is only supported with GRUB", 1);
+    }
+    if config.bootloader.r#type == BootloaderType::Refind
+        && config
+            .partition
+            .swap
+            .as_ref()
+            .is_some_and(|swap| swap.hibernate)
+    {
+        crash("Hibernation is only supported with GRUB", 1);
+    }
+}
+
+/// Runs the full installation described by a config,
+/// `yes` skips the confirmation before erasing disks
+pub fn install_config(config: Config, yes: bool) {
+    let image_file = image_file(&config);
+    let device = match &image_file {
+        Some(file) => {
+            log::info!("Image to install into : {}", file.display());
+            let size = config.partition.image.as_ref().and_then(|image| image.size);
+            image::attach(file, size)
+        }
+        None => dev_path(&config.partition.device),
+    };
+    log::info!("Block device to use : {}", device.display());
     log::info!("Partitioning mode : {:?}", config.partition.mode);
     log::info!("Partitioning for EFI : {}", config.partition.efi);
-    let mut partitions: Vec<args::Partition> = Vec::new();
-    for partition in config.partition.partitions {
-        partitions.push(args::Partition::new(
-            partition.split(':').collect::<Vec<&str>>()[0].to_string(),
-            partition.split(':').collect::<Vec<&str>>()[1].to_string(),
-            partition.split(':').collect::<Vec<&str>>()[2].to_string(),
-        ));
-    }
-    let device = PathBuf::from("/dev/").join(config.partition.device.as_str());
+    let partitions = manual_partitions(&config);
     partition::partition(
-        device,
+        device.clone(),
         config.partition.mode,
         config.partition.efi,
-        &mut partitions,
-        config.unakite.enable,
+        &partitions,
+        &auto_layout(&config),
+        yes,
     );
     base::install_base_packages(config.kernel);
-    base::genfstab();
+    base::genfstab(config.fstab_reference);
     println!();
-    log::info!("Installing bootloader : {}", config.bootloader.r#type);
+    log::info!("Installing bootloader : {:?}", config.bootloader.r#type);
     log::info!("Installing bootloader to : {}", config.bootloader.location);
-    if config.bootloader.r#type == "grub-efi" {
-        base::install_bootloader_grub_efi(PathBuf::from(config.bootloader.location));
-    } else if config.bootloader.r#type == "grub-legacy" {
-        base::install_bootloader_grub_legacy(PathBuf::from(config.bootloader.location));
-    } else if config.bootloader.r#type == "refind" {
-        if config.bootloader.device.is_none() {
-            crash("efi block device not specified", 1)
+    match config.bootloader.r#type {
+        BootloaderType::GrubEfi => {
+            base::install_bootloader_grub_efi(PathBuf::from(&config.bootloader.location));
+        }
+        BootloaderType::GrubLegacy => {
+            base::install_bootloader_grub_legacy(PathBuf::from(&config.bootloader.location));
+        }
+        BootloaderType::Refind => {
+            let device = match &config.bootloader.device {
+                Some(device) => device,
+                None => crash("efi block device not specified", 1),
+            };
+            // installs refind, currently assumes that it should be installed as the default BOOT entry
+            base::install_bootloader_refind(
+                PathBuf::from(&config.bootloader.location),
+                true,
+                PathBuf::from(device),
+            );
         }
-        // installs refind, currently assumes that it should be installed as the default BOOT entry
-        base::install_bootloader_refind(
-            PathBuf::from(config.bootloader.location),
-            true,
-            PathBuf::from(config.bootloader.device.unwrap()),
-        );
     }
     println!();
     log::info!("Adding Locales : {:?}", config.locale.locale);
@@ -160,45 +477,24 @@ pub fn read_config(configpath: PathBuf) {
     println!("---------");
     for i in 0..config.users.len() {
         log::info!("Creating user : {}", config.users[i].name);
-        log::info!("Setting use password : {}", config.users[i].password);
+        log::info!("Setting user password : {}", config.users[i].password);
         log::info!("Enabling root for user : {}", config.users[i].hasroot);
-        log::info!("Setting user shell : {}", config.users[i].shell);
+        log::info!("Setting user shell : {:?}", config.users[i].shell);
         users::new_user(
             config.users[i].name.as_str(),
             config.users[i].hasroot,
-            config.users[i].password.as_str(),
+            config.users[i].password.expose(),
             false,
-            config.users[i].shell.as_str(),
+            config.users[i].shell,
         );
         println!("---------");
     }
     println!();
     log::info!("Setting root password : {}", config.rootpass);
-    users::root_pass(config.rootpass.as_str());
+    users::root_pass(config.rootpass.expose());
     println!();
     log::info!("Installing desktop : {:?}", config.desktop);
-    /*if let Some(desktop) = &config.desktop {
-        desktops::install_desktop_setup(*desktop);
-    }*/
-    match config.desktop.to_lowercase().as_str() {
-        "onyx" => desktops::install_desktop_setup(DesktopSetup::Onyx),
-        "kde" => desktops::install_desktop_setup(DesktopSetup::Kde),
-        "plasma" => desktops::install_desktop_setup(DesktopSetup::Kde),
-        "mate" => desktops::install_desktop_setup(DesktopSetup::Mate),
-        "gnome" => desktops::install_desktop_setup(DesktopSetup::Gnome),
-        "cinnamon" => desktops::install_desktop_setup(DesktopSetup::Cinnamon),
-        "xfce" => desktops::install_desktop_setup(DesktopSetup::Xfce),
-        "budgie" => desktops::install_desktop_setup(DesktopSetup::Budgie),
-        "enlightenment" => desktops::install_desktop_setup(DesktopSetup::Enlightenment),
-        "lxqt" => desktops::install_desktop_setup(DesktopSetup::Lxqt),
-        "sway" => desktops::install_desktop_setup(DesktopSetup::Sway),
-        "i3" => desktops::install_desktop_setup(DesktopSetup::I3),
-        "herbstluftwm" => desktops::install_desktop_setup(DesktopSetup::Herbstluftwm),
-        "awesome" => desktops::install_desktop_setup(DesktopSetup::Awesome),
-        "bspwm" => desktops::install_desktop_setup(DesktopSetup::Bspwm),
-        "none/diy" => desktops::install_desktop_setup(DesktopSetup::None),
-        _ => log::info!("No desktop setup selected!"),
-    }
+    desktops::install_desktop_setup(config.desktop);
     println!();
     log::info!("Enabling timeshift : {}", config.timeshift);
     if config.timeshift {
@@ -215,32 +511,43 @@ pub fn read_config(configpath: PathBuf) {
         extra_packages.push(config.extra_packages[i].as_str());
     }
     install(extra_packages);
+    save_install_record(&config);
     log::info!("Setup unakite");
-    if config.partition.mode == PartitionMode::Auto
-        && !config.partition.efi
-        && config.unakite.enable
-        && !config.partition.device.to_string().contains("nvme")
-    {
-        let root = PathBuf::from("/dev/").join(config.partition.device.as_str());
-        unakite::setup_unakite(
-            format!("{}2", root.to_str().unwrap()).as_str(),
-            format!("{}3", root.to_str().unwrap()).as_str(),
-            config.partition.efi,
-            "/boot",
-            format!("{}1", root.to_str().unwrap()).as_str(),
-        )
-    } else if config.partition.mode == PartitionMode::Auto
-        && config.partition.efi
-        && config.unakite.enable
-        && !config.partition.device.to_string().contains("nvme")
+    if config.unakite.enable
+        && config.partition.mode == PartitionMode::Auto
+        && config.partition.layout.is_none()
     {
-        let root = PathBuf::from("/dev/").join(config.partition.device.as_str());
+        // where the partitions end up depends on the layout, e.g. a swap partition comes
+        // before the root, the crystal root can be encrypted and the EFI system partition
+        // can be an existing one on another disk
+        let state = state::load();
+        let boot = if config.partition.efi {
+            let layout = auto_layout(&config);
+            let esp = layout.partitions.into_iter().find(|p| p.is_esp());
+            esp.and_then(|esp| esp.mountpoint)
+        } else {
+            Some(String::from("/boot"))
+        };
+        let bootdev = state
+            .mounts
+            .iter()
+            .find(|mount| boot.as_ref() == Some(&mount.mountpoint))
+            .map(|mount| mount.device.clone());
+        let (Some(root), Some(oldroot), Some(bootdev)) =
+            (state.unakite_root, state.root_device, bootdev)
+        else {
+            crash("Partitioning didn't record the partitions for unakite", 1);
+        };
         unakite::setup_unakite(
-            format!("{}2", root.to_str().unwrap()).as_str(),
-            format!("{}3", root.to_str().unwrap()).as_str(),
+            &root,
+            &oldroot,
             config.partition.efi,
-            "/boot/efi",
-            format!("{}1", root.to_str().unwrap()).as_str(),
+            if config.partition.efi {
+                "/boot/efi"
+            } else {
+                "/boot"
+            },
+            &bootdev,
         )
     } else if config.unakite.enable {
         unakite::setup_unakite(
@@ -250,36 +557,31 @@ pub fn read_config(configpath: PathBuf) {
             &config.unakite.efidir,
             &config.unakite.bootdev,
         );
-    } else if config.partition.mode == PartitionMode::Auto
-        && config.partition.efi
-        && config.unakite.enable
-        && config.partition.device.to_string().contains("nvme")
-    {
-        let root = PathBuf::from("/dev/").join(config.partition.device.as_str());
-        unakite::setup_unakite(
-            format!("{}p2", root.to_str().unwrap()).as_str(),
-            format!("{}p3", root.to_str().unwrap()).as_str(),
-            config.partition.efi,
-            "/boot/efi",
-            format!("{}p1", root.to_str().unwrap()).as_str(),
-        )
-    } else if config.partition.mode == PartitionMode::Auto
-        && !config.partition.efi
-        && config.unakite.enable
-        && config.partition.device.to_string().contains("nvme")
-    {
-        let root = PathBuf::from("/dev/").join(config.partition.device.as_str());
-        unakite::setup_unakite(
-            format!("{}p2", root.to_str().unwrap()).as_str(),
-            format!("{}p3", root.to_str().unwrap()).as_str(),
-            config.partition.efi,
-            "/boot",
-            format!("{}p1", root.to_str().unwrap()).as_str(),
-        )
     } else {
         log::info!("Unakite disabled");
     }
-    println!("Installation finished! You may reboot now!")
+    if image_file.is_some() {
+        let qcow2 = config
+            .partition
+            .image
+            .as_ref()
+            .and_then(|i| i.qcow2.as_deref());
+        image::detach(qcow2);
+    }
+    match config.on_finish {
+        FinishAction::None => println!("Installation finished! You may reboot now!"),
+        FinishAction::Reboot => {
+            log::info!("Installation finished! Rebooting");
+            exec_eval(exec("systemctl", vec![String::from("reboot")]), "Reboot");
+        }
+        FinishAction::Poweroff => {
+            log::info!("Installation finished! Powering off");
+            exec_eval(
+                exec("systemctl", vec![String::from("poweroff")]),
+                "Power off",
+            );
+        }
+    }
 }
 
 /// compile a `Vec` of pacman/arch packages.
@@ -289,14 +591,55 @@ pub fn get_packages() -> Vec<String> {
     let mut packages: Vec<String> = pkg_buf.lines().map(|line| line.to_string()).collect();
     packages.retain(|line| {
         // filter out blank or commented lines
-        if line.is_empty() {
-            false
-        } else if line.starts_with("//") || line.starts_with("#") {
-            false
-        } else {
-            true
-        }
+        !(line.is_empty() || line.starts_with("//") || line.starts_with('#'))
     });
 
     packages
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(mode: &str) -> Config {
+        serde_json::from_str(&format!(
+            r#"{{
+                "partition": {{ "device": "sda", "mode": "{mode}", "efi": true, "partitions": [] }},
+                "bootloader": {{ "type": "grub-efi", "location": "/boot/efi" }},
+                "locale": {{ "locale": ["en_US.UTF-8 UTF-8"], "keymap": "us", "timezone": "UTC" }},
+                "networking": {{ "hostname": "jade", "ipv6": false }},
+                "users": [],
+                "rootpass": "secret",
+                "desktop": "onyx",
+                "timeshift": false,
+                "flatpak": false,
+                "zramd": false,
+                "extra_packages": [],
+                "unakite": {{
+                    "enable": false,
+                    "root": "/dev/sda2",
+                    "oldroot": "/dev/sda3",
+                    "efidir": "/boot/efi",
+                    "bootdev": "/dev/sda1"
+                }}
+            }}"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn records_resolved_layout() {
+        let record = serde_json::to_value(install_record(&config("Auto"))).unwrap();
+        let partitions: Vec<LayoutPartition> =
+            serde_json::from_value(record["layout"]["partitions"].clone()).unwrap();
+        let default = Layout::default_for(true, false).partitions;
+        assert_eq!(
+            serde_json::to_value(&partitions).unwrap(),
+            serde_json::to_value(&default).unwrap()
+        );
+        assert_eq!(record["config"]["rootpass"], "[redacted]");
+
+        let record = serde_json::to_value(install_record(&config("Manual"))).unwrap();
+        assert!(record["layout"].is_null());
+    }
+}
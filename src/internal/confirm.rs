@@ -0,0 +1,17 @@
+use crate::internal::*;
+use std::io::Write;
+
+/// Warns about a destructive action and makes the user type `expected` to go ahead,
+/// unless `yes` was passed on the command line
+pub fn confirm_destructive(action: &str, expected: &str, yes: bool) {
+    log::warn!("{action}");
+    if yes {
+        return;
+    }
+    print!("Type {expected} to continue: ");
+    std::io::stdout().flush().ok();
+    let mut input = String::new();
+    if std::io::stdin().read_line(&mut input).is_err() || input.trim() != expected {
+        crash("Aborted, nothing was changed", 1);
+    }
+}
@@ -1,4 +1,5 @@
-use std::process::Command;
+use std::io::Write;
+use std::process::{Command, Stdio};
 
 pub fn exec(command: &str, args: Vec<String>) -> Result<std::process::ExitStatus, std::io::Error> {
     let returncode = Command::new(command).args(args).status();
@@ -29,3 +30,23 @@ pub fn exec_workdir(
         .status();
     returncode
 }
+
+pub fn exec_stdin(
+    command: &str,
+    args: Vec<String>,
+    input: &str,
+) -> Result<std::process::ExitStatus, std::io::Error> {
+    let mut child = Command::new(command)
+        .args(args)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes())?;
+    }
+    child.wait()
+}
+
+pub fn exec_output(command: &str, args: Vec<String>) -> Result<String, std::io::Error> {
+    let output = Command::new(command).args(args).output()?;
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
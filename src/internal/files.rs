@@ -36,6 +36,13 @@ pub fn append_file(path: &str, content: &str) -> std::io::Result<()> {
     Ok(())
 }
 
+pub fn write_file(path: &str, content: &str) -> std::io::Result<()> {
+    log::info!("Write file {}", path);
+    let mut file = File::create(path)?;
+    file.write_all(content.as_bytes())?;
+    Ok(())
+}
+
 pub fn sed_file(path: &str, find: &str, replace: &str) -> std::io::Result<()> {
     log::info!("Sed '{}' to '{}' in file {}", find, replace, path);
     let contents = fs::read_to_string(path)?;
@@ -1,8 +1,12 @@
+pub mod auto;
 pub mod config;
+pub mod confirm;
 pub mod exec;
 pub mod files;
 pub mod install;
 pub mod returncode_eval;
+pub mod secret;
+pub mod state;
 pub mod strings;
 
 pub use install::install;
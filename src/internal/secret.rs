@@ -0,0 +1,84 @@
+use crate::logging;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::convert::Infallible;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const REDACTED: &str = "[redacted]";
+
+/// Where a secret in the config is read from
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SecretSource {
+    /// The secret is written inline in the config
+    Inline(String),
+    /// The secret is the content of a file, e.g. `{ "file": "/run/secrets/rootpass" }`
+    File { file: PathBuf },
+    /// The secret is the value of an environment variable, e.g. `{ "env": "JADE_ROOTPASS" }`
+    Env { env: String },
+}
+
+/// A password or password hash that must never show up in logs or saved configs.
+/// Displaying or serializing it prints a placeholder, use `expose` to get the real value.
+#[derive(Clone)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: String) -> Self {
+        logging::redact(&value);
+        Self(value)
+    }
+
+    /// Reads a secret from a file, ignoring a trailing newline
+    pub fn from_file(path: &Path) -> std::io::Result<Self> {
+        let value = std::fs::read_to_string(path)?;
+        Ok(Secret::new(value.trim_end_matches('\n').to_string()))
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for Secret {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Secret::new(s.to_string()))
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(REDACTED)
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = match SecretSource::deserialize(deserializer)? {
+            SecretSource::Inline(value) => value,
+            SecretSource::File { file } => {
+                return Secret::from_file(&file).map_err(|e| {
+                    serde::de::Error::custom(format!("Read secret from {file:?}: {e}"))
+                });
+            }
+            SecretSource::Env { env } => std::env::var(&env)
+                .map_err(|e| serde::de::Error::custom(format!("Read secret from ${env}: {e}")))?,
+        };
+        Ok(Secret::new(value))
+    }
+}
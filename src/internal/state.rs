@@ -0,0 +1,137 @@
+use crate::internal::*;
+use serde::{Deserialize, Serialize};
+
+/// Where the install state is kept on the live system
+const STATE_PATH: &str = "/tmp/jade-state.json";
+
+/// An encrypted volume that has to be unlocked at boot
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LuksVolume {
+    /// UUID of the LUKS container
+    pub uuid: String,
+    /// Name of the unlocked volume in /dev/mapper
+    pub name: String,
+    /// Extra crypttab options, like `tpm2-device=auto`
+    #[serde(default)]
+    pub options: Vec<String>,
+}
+
+/// A filesystem mounted below /mnt that goes into the fstab of the installed system
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mount {
+    /// The block device, e.g. a partition, logical volume or unlocked LUKS volume
+    pub device: String,
+    /// Where it's mounted in the installed system, e.g. `/home`
+    pub mountpoint: String,
+    /// The options it's mounted with, like `subvol=@home`
+    #[serde(default)]
+    pub options: Vec<String>,
+}
+
+/// What earlier install steps set up that later steps have to know about, e.g. partitioning
+/// encrypts the root and the bootloader has to pass the matching kernel parameters.
+/// This is kept in a file since every step can be run as a separate jade command.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct InstallState {
+    /// The encrypted root volume, unlocked by the initramfs
+    pub luks_root: Option<LuksVolume>,
+    /// Other encrypted volumes, unlocked through /etc/crypttab
+    pub luks_volumes: Vec<LuksVolume>,
+    /// mkinitcpio hooks the storage setup needs
+    pub initramfs_hooks: Vec<String>,
+    /// Packages the storage setup needs in the installed system
+    pub packages: Vec<String>,
+    /// Whether GRUB has to unlock the root to read /boot
+    pub grub_cryptodisk: bool,
+    /// Whether the initramfs has to be built with the systemd hook
+    pub systemd_initramfs: bool,
+    /// Block device of the root filesystem, e.g. a logical volume
+    pub root_device: Option<String>,
+    /// The partition of the default layout that unakite gets installed to
+    #[serde(default)]
+    pub unakite_root: Option<String>,
+    /// Other disks of a redundant RAID that need the BIOS bootloader as well
+    pub mirror_disks: Vec<String>,
+    /// Copies of the EFI system partition on the other disks of a redundant RAID
+    pub mirror_esps: Vec<String>,
+    /// The swap to resume from after hibernating, as `UUID=...`
+    pub resume: Option<String>,
+    /// Offset of the swapfile on the `resume` filesystem
+    pub resume_offset: Option<u64>,
+    /// Whether another OS is installed next to this one, GRUB has to look for it and
+    /// leave the fallback bootloader of the EFI system partition alone
+    pub dual_boot: bool,
+    /// The image file jade installs into instead of a disk
+    pub image: Option<String>,
+    /// The loop device the image is attached to
+    pub loop_device: Option<String>,
+    /// Everything partitioning mounted below /mnt
+    #[serde(default)]
+    pub mounts: Vec<Mount>,
+    /// Swap partitions and volumes, and swapfiles by their path in the installed system
+    #[serde(default)]
+    pub swaps: Vec<String>,
+}
+
+impl InstallState {
+    pub fn add_initramfs_hook(&mut self, hook: &str) {
+        if !self.initramfs_hooks.iter().any(|h| h == hook) {
+            self.initramfs_hooks.push(String::from(hook));
+        }
+    }
+
+    /// Records a mount, replacing whatever was mounted at the same place before
+    pub fn add_mount(&mut self, mount: Mount) {
+        self.mounts.retain(|m| m.mountpoint != mount.mountpoint);
+        self.mounts.push(mount);
+    }
+
+    pub fn add_swap(&mut self, swap: &str) {
+        if !self.swaps.iter().any(|s| s == swap) {
+            self.swaps.push(String::from(swap));
+        }
+    }
+
+    pub fn add_package(&mut self, package: &str) {
+        if !self.packages.iter().any(|p| p == package) {
+            self.packages.push(String::from(package));
+        }
+    }
+}
+
+/// Loads the install state, an install that hasn't saved any state yet gets the default
+pub fn load() -> InstallState {
+    match std::fs::read_to_string(STATE_PATH) {
+        Ok(data) => match serde_json::from_str(&data) {
+            Ok(state) => state,
+            Err(e) => crash(format!("Parse install state {STATE_PATH}  ERROR: {e}"), 1),
+        },
+        Err(_) => InstallState::default(),
+    }
+}
+
+fn save(state: &InstallState) {
+    let json = match serde_json::to_string_pretty(state) {
+        Ok(json) => json,
+        Err(e) => crash(format!("Serialize install state  ERROR: {e}"), 1),
+    };
+    files_eval(files::write_file(STATE_PATH, &json), "Save install state");
+}
+
+/// Changes the install state and saves it right away
+pub fn update<F: FnOnce(&mut InstallState)>(f: F) {
+    let mut state = load();
+    f(&mut state);
+    save(&state);
+}
+
+/// Forgets the state of earlier installs, done whenever partitioning starts over
+pub fn reset() {
+    // an image is attached before partitioning starts
+    let state = load();
+    save(&InstallState {
+        image: state.image,
+        loop_device: state.loop_device,
+        ..InstallState::default()
+    });
+}
@@ -4,12 +4,28 @@ use lazy_static::lazy_static;
 use log::{Level, LevelFilter};
 use std::env;
 use std::io::Write;
+use std::sync::Mutex;
 
 lazy_static! {
     static ref UWU: bool = env::var("JADE_UWU").map(|v| v == "true").unwrap_or(false);
     static ref UWU_DEBUG: bool = env::var("JADE_UWU_DEBUG")
         .map(|v| v == "true")
         .unwrap_or(false);
+    static ref SECRETS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+}
+
+const REDACTED: &str = "[redacted]";
+
+/// Registers a secret value that gets replaced in every log message from now on,
+/// wherever it stands on its own
+pub fn redact(secret: &str) {
+    if secret.trim().is_empty() {
+        return;
+    }
+    let mut secrets = SECRETS.lock().unwrap();
+    if !secrets.iter().any(|s| s == secret) {
+        secrets.push(secret.to_string());
+    }
 }
 
 pub fn init(verbosity: usize) {
@@ -36,7 +52,7 @@ fn format_log_entry(
     now: &mut DeferredNow,
     record: &log::Record,
 ) -> std::io::Result<()> {
-    let msg = record.args().to_string();
+    let msg = apply_redaction(record.args().to_string());
     let level = record.level();
     let msg = apply_uwu(level, msg);
     let (h, m, s) = now.now().time().as_hms();
@@ -51,6 +67,46 @@ fn format_log_entry(
     )
 }
 
+/// Replaces all registered secrets in a message
+fn apply_redaction(mut msg: String) -> String {
+    for secret in SECRETS.lock().unwrap().iter() {
+        msg = replace_word(&msg, secret);
+    }
+    msg
+}
+
+/// Whether `c` separates words in a log message
+fn is_separator(c: char) -> bool {
+    c.is_whitespace() || "\"'`=:,;()[]{}<>".contains(c)
+}
+
+/// Replaces `secret` where it is a whole word, so a short password like `1234` doesn't
+/// mangle device names, paths or sizes that happen to contain it
+fn replace_word(msg: &str, secret: &str) -> String {
+    let mut result = String::with_capacity(msg.len());
+    let mut rest = msg;
+    while let Some(start) = rest.find(secret) {
+        let end = start + secret.len();
+        let before = rest[..start]
+            .chars()
+            .next_back()
+            .or_else(|| result.chars().next_back());
+        let after = rest[end..].chars().next();
+        result.push_str(&rest[..start]);
+        if before.is_none_or(is_separator) && after.is_none_or(is_separator) {
+            result.push_str(REDACTED);
+            rest = &rest[end..];
+        } else {
+            // part of a longer word, look again from the next character
+            let next = start + rest[start..].chars().next().map_or(1, char::len_utf8);
+            result.push_str(&rest[start..next]);
+            rest = &rest[next..];
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
 /// Applies uwu if the required environment variables are set
 fn apply_uwu(level: Level, msg: String) -> String {
     match level {
@@ -70,3 +126,26 @@ fn apply_uwu(level: Level, msg: String) -> String {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_whole_words() {
+        assert_eq!(
+            replace_word("usermod --password 1234 jade", "1234"),
+            "usermod --password [redacted] jade"
+        );
+        assert_eq!(
+            replace_word("password=\"1234\"", "1234"),
+            "password=\"[redacted]\""
+        );
+        assert_eq!(
+            replace_word("Attached /tmp/a.img to /dev/loop1234, 1234MiB", "1234"),
+            "Attached /tmp/a.img to /dev/loop1234, 1234MiB"
+        );
+        assert_eq!(replace_word("a", "a"), "[redacted]");
+        assert_eq!(replace_word("aa a", "a"), "aa [redacted]");
+    }
+}
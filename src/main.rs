@@ -5,28 +5,88 @@ mod logging;
 
 use crate::args::{BootloaderSubcommand, Command, Opt, UsersSubcommand};
 use crate::functions::*;
-use clap::Parser;
+use crate::internal::crash;
+use crate::internal::secret::Secret;
+use clap::{CommandFactory, ErrorKind, Parser};
 
 fn main() {
-    human_panic::setup_panic!();
+    // the macro of human-panic 1.x still names std::panic::PanicInfo
+    #[allow(deprecated)]
+    {
+        human_panic::setup_panic!();
+    }
     let opt: Opt = Opt::parse();
     logging::init(opt.verbose);
     match opt.command {
         Command::Partition(args) => {
-            let mut partitions = args.partitions;
-            partition::partition(
-                args.device,
-                args.mode,
-                args.efi,
-                &mut partitions,
-                args.unakite,
-            );
+            let partitions = args.partitions;
+            if let Err(e) = partition::check_supported(args.root_filesystem.name()) {
+                Opt::command().error(ErrorKind::InvalidValue, e).exit();
+            }
+            let mut layout = layout::Layout::default_for(args.efi, args.unakite);
+            layout.set_root_filesystem(args.root_filesystem.name());
+            if args.lvm {
+                layout.use_lvm(None);
+            }
+            if let Err(e) = args::validate_partitions(&partitions, layout.lvm.as_ref()) {
+                Opt::command().error(ErrorKind::ValueValidation, e).exit();
+            }
+            if let Some(size) = args.swap {
+                layout.set_swap(layout::Swap {
+                    size,
+                    file: args.swapfile,
+                    hibernate: args.hibernate,
+                });
+            }
+            layout.wipe = args.wipe;
+            layout.disks = args.disks;
+            layout.esp = args.esp;
+            if let (Some(partition), Some(by)) = (args.shrink, args.shrink_by) {
+                layout.shrink = Some(layout::Shrink { partition, by });
+            }
+            if let Some(level) = args.raid {
+                layout.raid = Some(layout::Raid {
+                    level,
+                    backend: args.raid_backend,
+                    devices: args.raid_devices,
+                });
+            }
+            if let Some(path) = args.encrypt {
+                let passphrase = match Secret::from_file(&path) {
+                    Ok(passphrase) => passphrase,
+                    Err(e) => crash(format!("Read passphrase from {path:?}  ERROR: {e}"), 1),
+                };
+                layout.encryption = Some(layout::Encryption {
+                    passphrase,
+                    home: false,
+                    tpm2: args.tpm2.then(layout::Tpm2::default),
+                    fido2: args.fido2,
+                });
+            }
+            let image = image::is_image(&args.device) || args.image_size.is_some();
+            if image {
+                if let Err(e) = image::check_file(&args.device) {
+                    Opt::command().error(ErrorKind::ValueValidation, e).exit();
+                }
+            }
+            let device = if image {
+                image::attach(&args.device, args.image_size)
+            } else {
+                args.device.clone()
+            };
+            partition::partition(device, args.mode, args.efi, &partitions, &layout, args.yes);
+        }
+        Command::DetachImage { qcow2 } => {
+            image::detach(qcow2.as_deref());
+        }
+        Command::Disks { json } => {
+            disks::print_disks(json);
         }
         Command::InstallBase(args) => {
             base::install_base_packages(args.kernel);
         }
-        Command::GenFstab => {
-            base::genfstab();
+        Command::GenFstab { by } => {
+            base::genfstab(by);
         }
         Command::SetupTimeshift { bootloader } => base::setup_timeshift(bootloader),
         Command::Bootloader { subcommand } => match subcommand {
@@ -66,13 +126,13 @@ fn main() {
                 users::new_user(
                     &args.username,
                     args.hasroot,
-                    &args.password,
+                    args.password.expose(),
                     true,
-                    &args.shell,
+                    args.shell,
                 );
             }
             UsersSubcommand::RootPass { password } => {
-                users::root_pass(&password);
+                users::root_pass(password.expose());
             }
         },
         Command::Nix => {
@@ -90,8 +150,11 @@ fn main() {
                 &args.bootdev,
             );
         }
-        Command::Config { config } => {
-            crate::internal::config::read_config(config);
+        Command::Config { config, yes } => {
+            crate::internal::config::read_config(config, yes);
+        }
+        Command::Auto { label, timeout } => {
+            crate::internal::auto::auto_install(&label, timeout);
         }
         Command::Desktops { desktop } => {
             desktops::install_desktop_setup(desktop);
@@ -115,7 +178,7 @@ mod tests {
 
     #[test]
     fn std_method() {
-        let pkg_list = include_str!("../test.txt");
+        let pkg_list = include_str!("../packages.x86_64");
         // println!(pkg_list);
 
         for line in pkg_list.lines() {